@@ -0,0 +1,53 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Bencher, Criterion, Fun};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use tempfile::TempDir;
+
+const KEYS: u64 = 1000;
+
+/// A store holding `KEYS` keys, compacted so that every record is in a
+/// generation the writer has moved past.
+fn populated(options: KvStoreOptions) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = options.open(temp_dir.path()).unwrap();
+
+    for i in 0..KEYS {
+        store
+            .set(format!("key{}", i).into_bytes(), vec![b'v'; 256])
+            .unwrap();
+    }
+
+    store.compaction().unwrap();
+
+    (temp_dir, store)
+}
+
+/// Gets every key once, in an order that jumps around the log.
+fn get_all(b: &mut Bencher, options: &KvStoreOptions) {
+    let (_temp_dir, store) = populated(options.clone());
+    let keys: Vec<Vec<u8>> = (0..KEYS)
+        .map(|i| format!("key{}", i * 7919 % KEYS).into_bytes())
+        .collect();
+
+    b.iter(|| {
+        for key in &keys {
+            store.get(key.clone()).unwrap().unwrap();
+        }
+    });
+}
+
+fn get(c: &mut Criterion) {
+    let funs = vec![
+        Fun::new("read", |b, _: &()| get_all(b, &KvStoreOptions::new())),
+        Fun::new("mmap", |b, _: &()| {
+            get_all(b, &KvStoreOptions::new().mmap_reads(true))
+        }),
+    ];
+
+    c.bench_functions("get", funs, ());
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
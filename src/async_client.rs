@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{codec, prefix_end, CasOutcome, Command, Response, Result, Version, WriteBatch};
+
+/// The async counterpart of [`KvsClient`](crate::KvsClient).
+///
+/// Every request method returns a future, so many clients can share a
+/// single runtime thread.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    namespace: Option<String>,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<AsyncKvsClient> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+
+        Ok(AsyncKvsClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            namespace: None,
+        })
+    }
+
+    /// Sends every request from now on to the namespace called `namespace`,
+    /// like [`KvsClient::set_namespace`](crate::KvsClient::set_namespace).
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    pub async fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.request(Command::Get { key }).await?.into_result()
+    }
+
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request(Command::Set { key, value })
+            .await?
+            .into_result()?;
+
+        Ok(())
+    }
+
+    /// Sets a value that expires once `ttl` has passed.
+    pub async fn set_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.request(Command::SetWithTtl { key, value, ttl })
+            .await?
+            .into_result()?;
+
+        Ok(())
+    }
+
+    /// Returns how long `key` has left before it expires, or `None` if it
+    /// never does.
+    pub async fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.request(Command::Ttl { key }).await?.into_ttl()
+    }
+
+    pub async fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request(Command::Remove { key }).await?.into_result()?;
+
+        Ok(())
+    }
+
+    /// Applies every operation in `batch` atomically.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request(Command::Batch { batch })
+            .await?
+            .into_result()?;
+
+        Ok(())
+    }
+
+    /// Adds one to the integer stored at `key` and returns the result.
+    pub async fn incr(&mut self, key: Vec<u8>) -> Result<i64> {
+        self.request(Command::Incr { key }).await?.into_counter()
+    }
+
+    /// Subtracts one from the integer stored at `key` and returns the result.
+    pub async fn decr(&mut self, key: Vec<u8>) -> Result<i64> {
+        self.request(Command::Decr { key }).await?.into_counter()
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the result,
+    /// like [`KvsEngine::incr_by`](crate::KvsEngine::incr_by).
+    pub async fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.request(Command::IncrBy { key, delta })
+            .await?
+            .into_counter()
+    }
+
+    /// Applies `batch` if every watched key still holds the value given for
+    /// it, like [`KvsEngine::commit_batch`](crate::KvsEngine::commit_batch).
+    pub async fn exec(
+        &mut self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        self.request(Command::Exec { watched, batch })
+            .await?
+            .into_committed()
+    }
+
+    /// Replaces the value of `key` with `new` if it currently is `expected`,
+    /// like [`KvsEngine::compare_and_swap`](crate::KvsEngine::compare_and_swap).
+    pub async fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        self.request(Command::CompareAndSwap { key, expected, new })
+            .await?
+            .into_cas()
+    }
+
+    /// Returns the value `key` had as of sequence number `seq`, like
+    /// [`KvsEngine::get_at`](crate::KvsEngine::get_at).
+    pub async fn get_at(&mut self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        self.request(Command::GetAt { key, seq })
+            .await?
+            .into_result()
+    }
+
+    /// Returns the versions of `key` the server still keeps, newest first.
+    pub async fn history(&mut self, key: Vec<u8>, limit: Option<usize>) -> Result<Vec<Version>> {
+        self.request(Command::History { key, limit })
+            .await?
+            .into_history()
+    }
+
+    /// Returns the pairs with keys from `start` up to but not including
+    /// `end`, like [`KvsEngine::scan`](crate::KvsEngine::scan).
+    pub async fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.request(Command::Scan { start, end, limit })
+            .await?
+            .into_entries()
+    }
+
+    /// Returns every pair whose key starts with `prefix`.
+    pub async fn scan_prefix(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+
+        self.scan(prefix, end, None).await
+    }
+
+    /// Sends every command before reading any response, like
+    /// [`KvsClient::pipeline`](crate::KvsClient::pipeline).
+    pub async fn pipeline(&mut self, commands: Vec<Command>) -> Result<Vec<Response>> {
+        let count = commands.len();
+
+        for command in commands {
+            let command = self.in_namespace(command);
+
+            codec::write_frame_async(&mut self.writer, &command).await?;
+        }
+
+        self.writer.flush().await?;
+
+        let mut responses = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            responses.push(codec::read_frame_async(&mut self.reader).await?);
+        }
+
+        Ok(responses)
+    }
+
+    async fn request(&mut self, command: Command) -> Result<Response> {
+        let command = self.in_namespace(command);
+
+        codec::write_frame_async(&mut self.writer, &command).await?;
+        self.writer.flush().await?;
+
+        codec::read_frame_async(&mut self.reader).await
+    }
+
+    fn in_namespace(&self, command: Command) -> Command {
+        match &self.namespace {
+            Some(namespace) => command.in_namespace(namespace.as_str()),
+            None => command,
+        }
+    }
+}
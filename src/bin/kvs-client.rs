@@ -1,15 +1,15 @@
 extern crate clap;
 #[macro_use]
 extern crate failure;
-extern crate failure_derive;
 
-use clap::{App, Arg, SubCommand};
-use kvs::Command;
-use kvs::Response;
-use kvs::Result;
-use std::io::prelude::*;
-use std::io::Read;
-use std::net::TcpStream;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use kvs::{prefix_end, Result};
+use kvs::{CasOutcome, KvsClient};
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// How many pairs `scan` asks the server for at a time.
+const SCAN_PAGE_LEN: usize = 256;
 
 fn main() -> Result<()> {
     let matches = App::new("KVS Client")
@@ -18,61 +18,253 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("get")
                 .arg(Arg::with_name("KEY").required(true).index(1))
-                .arg(Arg::with_name("address").long("addr").takes_value(true)),
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
         )
         .subcommand(
             SubCommand::with_name("set")
                 .arg(Arg::with_name("KEY").required(true).index(1))
                 .arg(Arg::with_name("VALUE").required(true).index(2))
-                .arg(Arg::with_name("address").long("addr").takes_value(true)),
+                .arg(Arg::with_name("ttl").long("ttl").takes_value(true))
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("KEY").required(true).index(1))
-                .arg(Arg::with_name("address").long("addr").takes_value(true)),
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .arg(Arg::with_name("KEY").required(true).index(1))
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .arg(Arg::with_name("KEY").required(true).index(1))
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("new").long("new").takes_value(true))
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .arg(Arg::with_name("KEY").required(true).index(1))
+                .arg(Arg::with_name("limit").long("limit").takes_value(true))
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .arg(Arg::with_name("START").index(1))
+                .arg(Arg::with_name("end").long("end").takes_value(true))
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .conflicts_with_all(&["START", "end"]),
+                )
+                .arg(Arg::with_name("limit").long("limit").takes_value(true))
+                .arg(Arg::with_name("address").long("addr").takes_value(true))
+                .arg(format_arg())
+                .arg(namespace_arg()),
         )
         .get_matches();
 
-    let command = match matches.subcommand() {
-        ("get", Some(sub_m)) => Command::Get {
-            key: sub_m.value_of("KEY").unwrap().to_owned(),
-        },
-        ("set", Some(sub_m)) => Command::Set {
-            key: sub_m.value_of("KEY").unwrap().to_owned(),
-            value: sub_m.value_of("VALUE").unwrap().to_owned(),
+    let (name, sub_m) = matches.subcommand();
+    let sub_m = sub_m.unwrap();
+    let address = sub_m.value_of("address").unwrap_or("127.0.0.1:4000");
+    let format = sub_m.value_of("format").unwrap_or("utf8");
+    let mut client = KvsClient::connect(address)?;
+
+    client.set_namespace(sub_m.value_of("namespace").map(str::to_owned));
+
+    match name {
+        "get" => match client.get(parse(format, sub_m, "KEY")?)? {
+            Some(value) => {
+                let mut stdout = io::stdout();
+
+                stdout.write_all(&encode(format, &value))?;
+                writeln!(stdout)?;
+            }
+            None => println!("Key not found"),
         },
-        ("rm", Some(sub_m)) => Command::Remove {
-            key: sub_m.value_of("KEY").unwrap().to_owned(),
+        "set" => {
+            let key = parse(format, sub_m, "KEY")?;
+            let value = parse(format, sub_m, "VALUE")?;
+
+            match sub_m.value_of("ttl") {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl.parse()?))?,
+                None => client.set(key, value)?,
+            }
+        }
+        "rm" => client.remove(parse(format, sub_m, "KEY")?)?,
+        // Whole seconds, rounded up so a key about to expire shows 1.
+        "ttl" => match client.ttl(parse(format, sub_m, "KEY")?)? {
+            Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+            None => println!("No expiry"),
         },
-        _ => panic!(),
-    };
+        // Without --expected the key must not exist, and without --new it is
+        // removed.
+        "cas" => {
+            let key = parse(format, sub_m, "KEY")?;
+            let expected = parse_optional(format, sub_m, "expected")?;
+            let new = parse_optional(format, sub_m, "new")?;
+
+            if let CasOutcome::Conflict { current } = client.compare_and_swap(key, expected, new)? {
+                match current {
+                    Some(value) => {
+                        let mut stdout = io::stdout();
+
+                        stdout.write_all(&encode(format, &value))?;
+                        writeln!(stdout)?;
+                    }
+                    None => println!("Key not found"),
+                }
+
+                return Err(format_err!("Value did not match"));
+            }
+        }
+        // Newest first, one version per line with its sequence number.
+        "history" => {
+            let limit = match sub_m.value_of("limit") {
+                Some(limit) => Some(limit.parse()?),
+                None => None,
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+
+            for version in client.history(parse(format, sub_m, "KEY")?, limit)? {
+                write!(stdout, "{}\t", version.seq)?;
+
+                match version.value {
+                    Some(value) => stdout.write_all(&encode(format, &value))?,
+                    None => write!(stdout, "Key not found")?,
+                }
+
+                writeln!(stdout)?;
+            }
+        }
+        "scan" => {
+            let (start, end) = match sub_m.value_of("prefix") {
+                Some(_) => {
+                    let prefix = parse(format, sub_m, "prefix")?;
+                    let end = prefix_end(&prefix);
+
+                    (prefix, end)
+                }
+                None => (
+                    parse_optional(format, sub_m, "START")?.unwrap_or_default(),
+                    parse_optional(format, sub_m, "end")?,
+                ),
+            };
+            let limit = match sub_m.value_of("limit") {
+                Some(limit) => Some(limit.parse()?),
+                None => None,
+            };
 
-    let address = matches
-        .subcommand()
-        .1
-        .unwrap()
-        .value_of("address")
-        .unwrap_or("127.0.0.1:4000");
-    let mut stream = TcpStream::connect(address)?;
-
-    stream.write(serde_json::to_string(&command).unwrap().as_bytes())?;
-
-    let mut buffer = String::new();
-    stream.read_to_string(&mut buffer)?;
-
-    let response: Response = serde_json::from_str(&buffer)?;
-
-    if response.is_error() {
-        Err(format_err!("{}", response.error.unwrap()))
-    } else {
-        match matches.subcommand().0 {
-            "get" => match response.value {
-                Some(value) => println!("{}", value),
-                None => println!("Key not found"),
-            },
-            _ => {}
+            scan(&mut client, format, start, end, limit)?;
         }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Prints the pairs in a range one page at a time, so a large scan does not
+/// have to fit in a single response.
+fn scan(
+    client: &mut KvsClient,
+    format: &str,
+    mut start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    limit: Option<usize>,
+) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut remaining = limit.unwrap_or(usize::MAX);
+
+    while remaining > 0 {
+        let page_len = remaining.min(SCAN_PAGE_LEN);
+        let page = client.scan(start, end.clone(), Some(page_len))?;
+
+        for (key, value) in &page {
+            stdout.write_all(&encode(format, key))?;
+            stdout.write_all(b"\t")?;
+            stdout.write_all(&encode(format, value))?;
+            writeln!(stdout)?;
+        }
+
+        stdout.flush()?;
+
+        let full = page.len() == page_len;
+
+        remaining -= page.len();
+
+        match page.into_iter().last() {
+            // A full page may have more pairs after it, starting right after
+            // its last key.
+            Some((mut key, _)) if full && remaining > 0 => {
+                key.push(0);
+                start = key;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// How keys and values are written on the command line and printed back.
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["utf8", "hex", "base64"])
+}
+
+/// Which namespace the command runs in, instead of the default keyspace.
+fn namespace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("namespace")
+        .long("namespace")
+        .takes_value(true)
+}
+
+fn parse(format: &str, matches: &ArgMatches, name: &str) -> Result<Vec<u8>> {
+    Ok(parse_optional(format, matches, name)?.unwrap())
+}
+
+fn parse_optional(format: &str, matches: &ArgMatches, name: &str) -> Result<Option<Vec<u8>>> {
+    let arg = match matches.value_of(name) {
+        Some(arg) => arg,
+        None => return Ok(None),
+    };
+
+    let bytes = match format {
+        "hex" => hex::decode(arg)?,
+        "base64" => base64::decode(arg)?,
+        _ => arg.as_bytes().to_vec(),
+    };
+
+    Ok(Some(bytes))
+}
 
-        Ok(())
+fn encode(format: &str, bytes: &[u8]) -> Vec<u8> {
+    match format {
+        "hex" => hex::encode(bytes).into_bytes(),
+        "base64" => base64::encode(bytes).into_bytes(),
+        _ => bytes.to_vec(),
     }
 }
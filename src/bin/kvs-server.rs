@@ -1,17 +1,24 @@
 extern crate clap;
 
 use clap::{App, Arg};
-use kvs::{Command, KvStore, KvsEngine, Logger, Response, Result, SledKvStore};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    codec, Command, Durability, KeyNotFound, KvStoreOptions, KvsEngine, Logger, NoThreads,
+    Response, Result, SledKvStore,
+};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::net::TcpListener;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
-use std::str;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
 #[macro_use]
 extern crate failure;
 
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 
 static LOGGER: Logger = Logger;
 
@@ -26,57 +33,335 @@ fn main() -> Result<()> {
         .version(env!("CARGO_PKG_VERSION"))
         .arg(Arg::with_name("address").long("addr").takes_value(true))
         .arg(Arg::with_name("engine").long("engine").takes_value(true))
+        .arg(Arg::with_name("threads").long("threads").takes_value(true))
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .takes_value(true)
+                .possible_values(&["naive", "shared", "rayon"]),
+        )
+        .arg(
+            Arg::with_name("runtime")
+                .long("runtime")
+                .takes_value(true)
+                .possible_values(&["threads", "tokio"]),
+        )
+        .arg(Arg::with_name("sync").long("sync").takes_value(true))
         .get_matches();
 
     let address = matches.value_of("address").unwrap_or("127.0.0.1:4000");
     let engine = matches.value_of("engine").unwrap_or("kvs");
+    let pool = matches.value_of("pool").unwrap_or("shared");
+    let runtime = matches.value_of("runtime").unwrap_or("threads");
+    let threads = match matches.value_of("threads") {
+        Some(threads) => parse_threads(threads)?,
+        None => thread::available_parallelism().map_or(4, |n| n.get() as u32),
+    };
+    // Without --sync each engine keeps its own default: kvs leaves flushing
+    // to the OS and sled flushes every write.
+    let sync = match matches.value_of("sync") {
+        Some(sync) => parse_sync(sync)?,
+        None if engine == "sled" => Durability::EveryWrite,
+        None => Durability::Never,
+    };
 
     info!(target: "address", "{:?}", address);
     info!(target: "engine", "{:?}", engine);
+    info!(target: "runtime", "{:?}", runtime);
+    info!(target: "pool", "{:?} with {} threads", pool, threads);
+    info!(target: "sync", "{:?}", sync);
 
     let dir = std::env::current_dir().unwrap();
 
     check_engine(engine, &dir)?;
 
-    let mut store: Box<dyn KvsEngine> = match engine {
-        "kvs" => Box::new(KvStore::open(dir)?),
-        "sled" => Box::new(SledKvStore::open(dir)?),
+    let listener = TcpListener::bind(address)?;
+
+    match engine {
+        "kvs" => {
+            let store = KvStoreOptions::new().durability(sync).open(dir)?;
+
+            run(store, runtime, pool, threads, listener)
+        }
+        "sled" => {
+            let store = SledKvStore::open_with(dir, sync)?;
+
+            run(store, runtime, pool, threads, listener)
+        }
         _ => panic!("unknown store"),
-    };
+    }
+}
 
-    let listener = TcpListener::bind(address)?;
+fn run<E: KvsEngine>(
+    store: E,
+    runtime: &str,
+    pool: &str,
+    threads: u32,
+    listener: TcpListener,
+) -> Result<()> {
+    match (runtime, pool) {
+        ("tokio", _) => serve_async(threads, listener, store),
+        (_, "naive") => serve(NaiveThreadPool::new(threads)?, listener, store),
+        (_, "rayon") => serve(RayonThreadPool::new(threads)?, listener, store),
+        _ => serve(SharedQueueThreadPool::new(threads)?, listener, store),
+    }
+}
+
+fn serve<P, E>(pool: P, listener: TcpListener, store: E) -> Result<()>
+where
+    P: ThreadPool + Send + Sync + 'static,
+    E: KvsEngine,
+{
+    // Each connection gets a thread of its own that waits for requests, so an
+    // idle client never holds one of the pool's workers. Only the requests
+    // run on the pool, so `--threads` bounds how many run at once but not
+    // how many connections are open: each costs a thread until it closes.
+    let pool = Arc::new(pool);
 
     for stream in listener.incoming() {
-        let mut stream = stream?;
-        let mut data = [0 as u8; 128];
-        let size = stream.read(&mut data)?;
-        let data_vec = data[..size].to_vec();
-        let string = str::from_utf8(&data_vec)?;
+        let stream = stream?;
+        let store = store.clone();
+        let pool = Arc::clone(&pool);
+
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &*pool, store) {
+                error!("connection error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
 
-        let result = get_result(string, &mut store);
-        let response = Response::new(result);
+fn handle_connection<P: ThreadPool, E: KvsEngine>(
+    stream: TcpStream,
+    pool: &P,
+    mut store: E,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
 
-        stream.write(serde_json::to_string(&response).unwrap().as_bytes())?;
+    while !reader.fill_buf()?.is_empty() {
+        let payload = match codec::read_payload(&mut reader) {
+            Ok(payload) => payload,
+            Err(e) => {
+                // A bad header leaves the stream out of sync, so answer once
+                // and drop the connection.
+                let response = Response::new(Err(format_err!("{}", e)));
+
+                codec::write_frame(&mut writer, &response)?;
+                writer.flush()?;
+
+                return Err(e);
+            }
+        };
+
+        // The handle moves to the worker and back so its open read handle
+        // is reused. A job that panics drops the sender, which ends the
+        // connection.
+        let (sender, receiver) = mpsc::channel();
+
+        pool.spawn(move || {
+            let response = respond(&payload, &store);
+
+            let _ = sender.send((store, response));
+        });
+
+        let (returned, response) = receiver.recv()?;
+
+        store = returned;
+
+        codec::write_frame(&mut writer, &response)?;
+
+        // Pipelined requests already buffered are answered before flushing.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
     }
 
+    writer.flush()?;
+
     Ok(())
 }
 
-fn get_result(string: &str, store: &mut Box<dyn KvsEngine>) -> Result<Option<String>> {
-    let result = match serde_json::from_str(string).unwrap() {
+fn serve_async<E: KvsEngine>(threads: u32, listener: TcpListener, store: E) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads.max(1) as usize)
+        .enable_io()
+        .build()?;
+
+    listener.set_nonblocking(true)?;
+
+    runtime.block_on(accept_async(listener, store))
+}
+
+async fn accept_async<E: KvsEngine>(listener: TcpListener, store: E) -> Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection_async(stream, store).await {
+                error!("connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection_async<E: KvsEngine>(
+    stream: tokio::net::TcpStream,
+    mut store: E,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut writer = tokio::io::BufWriter::new(writer);
+
+    while !reader.fill_buf().await?.is_empty() {
+        let payload = match codec::read_payload_async(&mut reader).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                let response = Response::new(Err(format_err!("{}", e)));
+
+                codec::write_frame_async(&mut writer, &response).await?;
+                writer.flush().await?;
+
+                return Err(e);
+            }
+        };
+
+        // Engine calls hit the disk, so they run on the blocking pool. The
+        // handle moves there and back so its open read handle is reused.
+        let (returned, response) = tokio::task::spawn_blocking(move || {
+            let response = respond(&payload, &store);
+
+            (store, response)
+        })
+        .await?;
+
+        store = returned;
+
+        codec::write_frame_async(&mut writer, &response).await?;
+
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Answers the command in `payload`. A response too large to send, such as
+/// that of a scan over too many pairs, is replaced with an error, so the
+/// client hears why rather than losing the connection.
+fn respond<E: KvsEngine>(payload: &[u8], store: &E) -> Response {
+    codec::decode(payload)
+        .and_then(|command| get_result(command, store))
+        .and_then(|response| {
+            codec::check_frame_len(&response)?;
+
+            Ok(response)
+        })
+        .unwrap_or_else(|e| Response::new(Err(e)))
+}
+
+fn get_result<E: KvsEngine>(command: Command, store: &E) -> Result<Response> {
+    let response = match command {
         Command::Set { key, value } => {
             store.set(key, value)?;
 
-            None
+            Response::new(Ok(None))
         }
-        Command::Get { key } => store.get(key)?,
+        Command::Get { key } => Response::new(Ok(store.get(key)?)),
         Command::Remove { key } => {
             store.remove(key)?;
-            None
+            Response::new(Ok(None))
+        }
+        Command::SetWithTtl { key, value, ttl } => {
+            store.set_with_ttl(key, value, ttl)?;
+            Response::new(Ok(None))
         }
+        Command::Ttl { key } => Response::with_ttl(store.ttl(key)?),
+        Command::Batch { batch } => {
+            store.write_batch(batch)?;
+            Response::new(Ok(None))
+        }
+        Command::Scan { start, end, limit } => {
+            Response::with_entries(store.scan(start, end, limit)?)
+        }
+        Command::Incr { key } => Response::with_counter(store.incr(key)?),
+        Command::Decr { key } => Response::with_counter(store.decr(key)?),
+        Command::IncrBy { key, delta } => Response::with_counter(store.incr_by(key, delta)?),
+        Command::GetAt { key, seq } => Response::new(Ok(store.get_at(key, seq)?)),
+        Command::History { key, limit } => Response::with_history(store.history(key, limit)?),
+        Command::Exec { watched, batch } => {
+            Response::with_committed(store.commit_batch(watched, batch)?)
+        }
+        Command::CompareAndSwap { key, expected, new } => {
+            Response::with_cas(store.compare_and_swap(key, expected, new)?)
+        }
+        // Reads must not create a namespace, or a typo in a name would leave
+        // a new one behind for good.
+        Command::Namespaced { namespace, command } if reads_only(&command) => {
+            match store.existing_namespace(&namespace)? {
+                Some(store) => get_result(*command, &store)?,
+                None => get_empty_result(*command)?,
+            }
+        }
+        Command::Namespaced { namespace, command } => {
+            get_result(*command, &store.namespace(&namespace)?)?
+        }
+    };
+
+    Ok(response)
+}
+
+fn reads_only(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Get { .. }
+            | Command::Ttl { .. }
+            | Command::Scan { .. }
+            | Command::GetAt { .. }
+            | Command::History { .. }
+    )
+}
+
+/// Answers a read-only command as an empty namespace would.
+fn get_empty_result(command: Command) -> Result<Response> {
+    let response = match command {
+        Command::Ttl { .. } => return Err(KeyNotFound.into()),
+        Command::Scan { .. } => Response::with_entries(Vec::new()),
+        Command::History { .. } => Response::with_history(Vec::new()),
+        _ => Response::new(Ok(None)),
     };
 
-    Ok(result)
+    Ok(response)
+}
+
+/// Parses `--sync`: `never`, `every-write` or `every-<N>ms`.
+fn parse_threads(threads: &str) -> Result<u32> {
+    match threads.parse()? {
+        0 => Err(NoThreads.into()),
+        threads => Ok(threads),
+    }
+}
+
+fn parse_sync(sync: &str) -> Result<Durability> {
+    match sync {
+        "never" => Ok(Durability::Never),
+        "every-write" => Ok(Durability::EveryWrite),
+        _ => match sync
+            .strip_prefix("every-")
+            .and_then(|interval| interval.strip_suffix("ms"))
+        {
+            Some(ms) => Ok(Durability::Interval(Duration::from_millis(ms.parse()?))),
+            None => Err(format_err!("Unknown sync mode {:?}", sync)),
+        },
+    }
 }
 
 fn check_engine(engine: &str, dir: &PathBuf) -> Result<()> {
@@ -104,7 +389,7 @@ impl EngineStore {
             .read(true)
             .append(true)
             .create(true)
-            .open(&path.join("engine_store"))
+            .open(path.join("engine_store"))
             .unwrap();
 
         Ok(EngineStore { file })
@@ -0,0 +1,207 @@
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::engines::transaction::{self, Source, Watched};
+use crate::{
+    codec, prefix_end, CasOutcome, Command, Response, Result, Transaction, Version, WriteBatch,
+};
+
+/// A connection to a `kvs-server` that can be reused for many requests.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    namespace: Option<String>,
+}
+
+impl KvsClient {
+    pub fn connect(address: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(address)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let writer = BufWriter::new(stream);
+
+        Ok(KvsClient {
+            reader,
+            writer,
+            namespace: None,
+        })
+    }
+
+    /// Sends every request from now on to the namespace called `namespace`,
+    /// or back to the default keyspace with `None`. See
+    /// [`KvsEngine::namespace`](crate::KvsEngine::namespace).
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.request(Command::Get { key })?.into_result()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request(Command::Set { key, value })?.into_result()?;
+
+        Ok(())
+    }
+
+    /// Sets a value that expires once `ttl` has passed.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.request(Command::SetWithTtl { key, value, ttl })?
+            .into_result()?;
+
+        Ok(())
+    }
+
+    /// Returns how long `key` has left before it expires, or `None` if it
+    /// never does.
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.request(Command::Ttl { key })?.into_ttl()
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request(Command::Remove { key })?.into_result()?;
+
+        Ok(())
+    }
+
+    /// Applies every operation in `batch` atomically.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request(Command::Batch { batch })?.into_result()?;
+
+        Ok(())
+    }
+
+    /// Adds one to the integer stored at `key` and returns the result.
+    pub fn incr(&mut self, key: Vec<u8>) -> Result<i64> {
+        self.request(Command::Incr { key })?.into_counter()
+    }
+
+    /// Subtracts one from the integer stored at `key` and returns the result.
+    pub fn decr(&mut self, key: Vec<u8>) -> Result<i64> {
+        self.request(Command::Decr { key })?.into_counter()
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the result,
+    /// like [`KvsEngine::incr_by`](crate::KvsEngine::incr_by).
+    pub fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.request(Command::IncrBy { key, delta })?.into_counter()
+    }
+
+    /// Applies `batch` if every watched key still holds the value given for
+    /// it, like [`KvsEngine::commit_batch`](crate::KvsEngine::commit_batch).
+    pub fn exec(
+        &mut self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        self.request(Command::Exec { watched, batch })?
+            .into_committed()
+    }
+
+    /// Runs `f` as a transaction against the server, like
+    /// [`KvsEngine::transaction`](crate::KvsEngine::transaction).
+    ///
+    /// Reads are sent as they happen and the writes go out in a single
+    /// [`exec`](Self::exec), which the server only applies if none of the
+    /// keys read have changed since.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<'_>) -> Result<T>,
+    {
+        transaction::run(self, f)
+    }
+
+    /// Replaces the value of `key` with `new` if it currently is `expected`,
+    /// like [`KvsEngine::compare_and_swap`](crate::KvsEngine::compare_and_swap).
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        self.request(Command::CompareAndSwap { key, expected, new })?
+            .into_cas()
+    }
+
+    /// Returns the value `key` had as of sequence number `seq`, like
+    /// [`KvsEngine::get_at`](crate::KvsEngine::get_at).
+    pub fn get_at(&mut self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        self.request(Command::GetAt { key, seq })?.into_result()
+    }
+
+    /// Returns the versions of `key` the server still keeps, newest first.
+    pub fn history(&mut self, key: Vec<u8>, limit: Option<usize>) -> Result<Vec<Version>> {
+        self.request(Command::History { key, limit })?
+            .into_history()
+    }
+
+    /// Returns the pairs with keys from `start` up to but not including
+    /// `end`, like [`KvsEngine::scan`](crate::KvsEngine::scan).
+    pub fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.request(Command::Scan { start, end, limit })?
+            .into_entries()
+    }
+
+    /// Returns every pair whose key starts with `prefix`.
+    pub fn scan_prefix(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+
+        self.scan(prefix, end, None)
+    }
+
+    /// Sends every command before reading any response, then returns the
+    /// responses in the order the commands were sent.
+    ///
+    /// The whole batch is written up front, so very large pipelines should be
+    /// split by the caller to keep both socket buffers from filling up.
+    pub fn pipeline(&mut self, commands: Vec<Command>) -> Result<Vec<Response>> {
+        let count = commands.len();
+
+        for command in commands {
+            let command = self.in_namespace(command);
+
+            codec::write_frame(&mut self.writer, &command)?;
+        }
+
+        self.writer.flush()?;
+
+        let mut responses = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            responses.push(codec::read_frame(&mut self.reader)?);
+        }
+
+        Ok(responses)
+    }
+
+    fn request(&mut self, command: Command) -> Result<Response> {
+        let command = self.in_namespace(command);
+
+        codec::write_frame(&mut self.writer, &command)?;
+        self.writer.flush()?;
+
+        codec::read_frame(&mut self.reader)
+    }
+
+    fn in_namespace(&self, command: Command) -> Command {
+        match &self.namespace {
+            Some(namespace) => command.in_namespace(namespace.as_str()),
+            None => command,
+        }
+    }
+}
+
+impl Source for &mut KvsClient {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KvsClient::get(self, key)
+    }
+
+    fn commit(&mut self, watched: Watched, batch: WriteBatch) -> Result<bool> {
+        self.exec(watched, batch)
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::{Read, Write};
+
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Result;
+
+/// Version byte written at the start of every frame. Version 1 carried JSON
+/// payloads; version 2 payloads are bincode.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Largest payload a peer is allowed to announce in a frame header.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Size of the frame header: one version byte and a big-endian `u32` length.
+pub const HEADER_LEN: usize = 5;
+
+#[derive(Fail, Debug)]
+pub enum FrameError {
+    #[fail(display = "Unsupported protocol version {}", _0)]
+    Version(u8),
+    #[fail(display = "Frame of {} bytes exceeds the {} byte limit", _0, _1)]
+    TooLarge(u32, u32),
+}
+
+/// Serializes `message` and writes it to `writer` as a single frame.
+///
+/// The writer is not flushed, so several frames can be buffered and sent
+/// together.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    writer.write_all(&encode(message)?)?;
+
+    Ok(())
+}
+
+/// Async counterpart of [`write_frame`]; the writer is not flushed either.
+pub async fn write_frame_async<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    writer.write_all(&encode(message)?).await?;
+
+    Ok(())
+}
+
+/// Checks that `message` fits in a single frame without encoding it.
+pub fn check_frame_len<T: Serialize>(message: &T) -> Result<()> {
+    let len = bincode::serialized_size(message)?;
+
+    if len > u64::from(MAX_FRAME_LEN) {
+        return Err(Error::from(FrameError::TooLarge(
+            len.min(u64::from(u32::MAX)) as u32,
+            MAX_FRAME_LEN,
+        )));
+    }
+
+    Ok(())
+}
+
+fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(message)?;
+
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(Error::from(FrameError::TooLarge(
+            payload.len() as u32,
+            MAX_FRAME_LEN,
+        )));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+
+    frame.push(PROTOCOL_VERSION);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+
+    Ok(frame)
+}
+
+/// Reads one frame from `reader` and returns its raw payload.
+///
+/// A header with an unknown version or an oversized length is rejected
+/// before any of the payload is read.
+pub fn read_payload<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_LEN];
+
+    reader.read_exact(&mut header)?;
+
+    let mut payload = vec![0u8; payload_len(&header)?];
+
+    reader.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
+
+/// Async counterpart of [`read_payload`].
+pub async fn read_payload_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_LEN];
+
+    reader.read_exact(&mut header).await?;
+
+    let mut payload = vec![0u8; payload_len(&header)?];
+
+    reader.read_exact(&mut payload).await?;
+
+    Ok(payload)
+}
+
+fn payload_len(header: &[u8; HEADER_LEN]) -> Result<usize> {
+    if header[0] != PROTOCOL_VERSION {
+        return Err(Error::from(FrameError::Version(header[0])));
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+
+    if len > MAX_FRAME_LEN {
+        return Err(Error::from(FrameError::TooLarge(len, MAX_FRAME_LEN)));
+    }
+
+    Ok(len as usize)
+}
+
+/// Deserializes a payload returned by [`read_payload`].
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(payload)?)
+}
+
+/// Reads one frame from `reader` and deserializes its payload.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    decode(&read_payload(reader)?)
+}
+
+/// Async counterpart of [`read_frame`].
+pub async fn read_frame_async<R, T>(reader: &mut R) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    decode(&read_payload_async(reader).await?)
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of sets and removes that [`KvsEngine::write_batch`] applies
+/// atomically: after a crash either every operation is visible or none is.
+///
+/// Operations apply in the order they were added. Removing a key that does
+/// not exist is not an error inside a batch.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"key1".to_vec(), b"value1".to_vec());
+/// batch.set(b"key2".to_vec(), b"value2".to_vec());
+/// batch.remove(b"key1".to_vec());
+/// store.write_batch(batch).unwrap();
+///
+/// assert_eq!(store.get(b"key1".to_vec()).unwrap(), None);
+/// assert_eq!(store.get(b"key2".to_vec()).unwrap(), Some(b"value2".to_vec()));
+/// ```
+///
+/// [`KvsEngine::write_batch`]: crate::KvsEngine::write_batch
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// The number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The key of every operation, in order.
+    pub(crate) fn keys(&self) -> Vec<Vec<u8>> {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.clone(),
+            })
+            .collect()
+    }
+}
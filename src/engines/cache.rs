@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{CasOutcome, KvsEngine, Version, WriteBatch};
+use crate::{KeyNotFound, Result};
+
+/// A [`KvsEngine`] that keeps recently read values in memory in front of
+/// another engine, so hot keys are not read from disk on every `get`.
+///
+/// The cache holds at most its capacity in bytes of keys and values, and
+/// evicts the least recently used pairs to stay under it. Every write made
+/// through the handle, or through its clones and namespaces, drops the keys
+/// it touches from the cache, so gets never see a value that was replaced.
+/// Writes made through the wrapped engine directly bypass the cache.
+///
+/// Compaction moves values around on disk but never changes them, so cached
+/// values stay valid across it. A key that expires is only cached until it
+/// does.
+///
+/// ```
+/// use kvs::{CachedEngine, KvStore, KvsEngine};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = CachedEngine::new(KvStore::open(temp_dir.path()).unwrap(), 1 << 20);
+///
+/// store.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+/// store.get(b"key".to_vec()).unwrap();
+/// store.get(b"key".to_vec()).unwrap();
+///
+/// assert_eq!(store.stats().misses, 1);
+/// assert_eq!(store.stats().hits, 1);
+/// ```
+#[derive(Clone)]
+pub struct CachedEngine<E: KvsEngine> {
+    engine: E,
+    // The namespace `engine` is a handle to, empty for the default keyspace.
+    // Namespaces share the cache, so their keys are told apart by it.
+    namespace: String,
+    cache: Arc<Mutex<Lru>>,
+}
+
+/// Counters for a [`CachedEngine`], from [`CachedEngine::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Gets answered from the cache.
+    pub hits: u64,
+    /// Gets that went to the engine.
+    pub misses: u64,
+    /// Pairs dropped to make room for others.
+    pub evictions: u64,
+    /// Pairs in the cache right now.
+    pub entries: u64,
+    /// Bytes of keys and values in the cache right now.
+    pub bytes: u64,
+}
+
+impl<E: KvsEngine> CachedEngine<E> {
+    /// Puts a cache of at most `capacity` bytes in front of `engine`.
+    pub fn new(engine: E, capacity: u64) -> CachedEngine<E> {
+        CachedEngine {
+            engine,
+            namespace: String::new(),
+            cache: Arc::new(Mutex::new(Lru::new(capacity))),
+        }
+    }
+
+    /// Returns the counters of the cache, which every namespace shares.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats.clone()
+    }
+
+    /// The engine behind the cache, for what it offers beyond [`KvsEngine`],
+    /// such as [`KvStore::compaction`](crate::KvStore::compaction). Writes
+    /// made through it are not seen by the cache.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    fn cache_key(&self, key: Vec<u8>) -> CacheKey {
+        (self.namespace.clone(), key)
+    }
+
+    /// Drops `keys` from the cache once they have been written.
+    fn invalidate(&self, keys: impl IntoIterator<Item = Vec<u8>>) {
+        let mut cache = self.cache.lock().unwrap();
+
+        cache.writes += 1;
+
+        for key in keys {
+            cache.remove(&self.cache_key(key));
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachedEngine<E> {
+    type Snapshot = E::Snapshot;
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let key = self.cache_key(key);
+
+        let writes = {
+            let mut cache = self.cache.lock().unwrap();
+
+            if let Some(value) = cache.get(&key) {
+                return Ok(Some(value));
+            }
+
+            cache.writes
+        };
+
+        let value = match self.engine.get(key.1.clone())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // Taken before asking, so the entry expires no later than the key.
+        let now = Instant::now();
+        let expires = match self.engine.ttl(key.1.clone()) {
+            Ok(ttl) => ttl.and_then(|ttl| now.checked_add(ttl)),
+            // Gone again since the get, so there is nothing to cache.
+            Err(e) if e.downcast_ref::<KeyNotFound>().is_some() => return Ok(Some(value)),
+            Err(e) => return Err(e),
+        };
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, value.clone(), expires, writes);
+
+        Ok(Some(value))
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let result = self.engine.set(key.clone(), value);
+
+        self.invalidate([key]);
+
+        result
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let result = self.engine.remove(key.clone());
+
+        self.invalidate([key]);
+
+        result
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let result = self.engine.set_with_ttl(key.clone(), value, ttl);
+
+        self.invalidate([key]);
+
+        result
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys = batch.keys();
+        let result = self.engine.write_batch(batch);
+
+        self.invalidate(keys);
+
+        result
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.engine.scan(start, end, limit)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let result = self.engine.compare_and_swap(key.clone(), expected, new);
+
+        self.invalidate([key]);
+
+        result
+    }
+
+    fn get_at(&self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        self.engine.get_at(key, seq)
+    }
+
+    fn history(&self, key: Vec<u8>, limit: Option<usize>) -> Result<Vec<Version>> {
+        self.engine.history(key, limit)
+    }
+
+    fn snapshot(&self) -> Result<E::Snapshot> {
+        self.engine.snapshot()
+    }
+
+    fn namespace(&self, name: &str) -> Result<CachedEngine<E>> {
+        Ok(CachedEngine {
+            engine: self.engine.namespace(name)?,
+            namespace: name.to_owned(),
+            cache: Arc::clone(&self.cache),
+        })
+    }
+
+    fn existing_namespace(&self, name: &str) -> Result<Option<CachedEngine<E>>> {
+        Ok(self
+            .engine
+            .existing_namespace(name)?
+            .map(|engine| CachedEngine {
+                engine,
+                namespace: name.to_owned(),
+                cache: Arc::clone(&self.cache),
+            }))
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<bool> {
+        let result = self.engine.drop_namespace(name);
+
+        let mut cache = self.cache.lock().unwrap();
+
+        cache.writes += 1;
+        cache.remove_namespace(name);
+
+        result
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.engine.namespaces()
+    }
+
+    fn commit_batch(
+        &self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let keys = batch.keys();
+        let result = self.engine.commit_batch(watched, batch);
+
+        self.invalidate(keys);
+
+        result
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let result = self.engine.incr_by(key.clone(), delta);
+
+        self.invalidate([key]);
+
+        result
+    }
+}
+
+/// A namespace, empty for the default keyspace, and a key in it.
+type CacheKey = (String, Vec<u8>);
+
+/// The pairs in the cache, in the order they were last used.
+struct Lru {
+    capacity: u64,
+    entries: HashMap<CacheKey, Entry>,
+    // Keys by when they were last used, least recently first.
+    recency: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    // Bumped by every write. A get only caches what it read if no write came
+    // in between, as what it read may already be stale.
+    writes: u64,
+    stats: CacheStats,
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+    used: u64,
+}
+
+impl Lru {
+    fn new(capacity: u64) -> Lru {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            writes: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the cached value of `key` and marks it as just used.
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry
+                .expires
+                .is_some_and(|expires| expires <= Instant::now()),
+            None => {
+                self.stats.misses += 1;
+
+                return None;
+            }
+        };
+
+        if expired {
+            self.remove(key);
+            self.stats.misses += 1;
+
+            return None;
+        }
+
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(key).unwrap();
+        let key = self.recency.remove(&entry.used).unwrap();
+
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key);
+        self.stats.hits += 1;
+
+        Some(entry.value.clone())
+    }
+
+    /// Caches `value` for `key`, evicting the least recently used pairs to
+    /// make room, unless there was a write since the get that read it at
+    /// `writes`. A pair bigger than the whole cache is not cached.
+    fn insert(&mut self, key: CacheKey, value: Vec<u8>, expires: Option<Instant>, writes: u64) {
+        let size = entry_size(&key, &value);
+
+        if writes != self.writes || size > self.capacity {
+            return;
+        }
+
+        self.remove(&key);
+
+        while self.stats.bytes + size > self.capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+
+            self.stats.bytes -= entry_size(&oldest, &entry.value);
+            self.stats.entries -= 1;
+            self.stats.evictions += 1;
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                used: self.clock,
+            },
+        );
+        self.stats.bytes += size;
+        self.stats.entries += 1;
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.stats.bytes -= entry_size(key, &entry.value);
+            self.stats.entries -= 1;
+        }
+    }
+
+    /// Drops every pair in the namespace called `name`.
+    fn remove_namespace(&mut self, name: &str) {
+        let keys: Vec<CacheKey> = self
+            .entries
+            .keys()
+            .filter(|(namespace, _)| namespace == name)
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            self.remove(key);
+        }
+    }
+}
+
+/// What a pair counts for against the capacity: the bytes of its namespace,
+/// key and value.
+fn entry_size((namespace, key): &CacheKey, value: &[u8]) -> u64 {
+    (namespace.len() + key.len() + value.len()) as u64
+}
@@ -0,0 +1,555 @@
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crossbeam_channel::{Receiver, Sender};
+use log::{error, warn};
+
+use super::hint::{self, Hint};
+use super::record::{self, FILE_HEADER};
+use super::snapshot::{Collected, Pins};
+use super::value::{self, ValuePos, ValueReaders, VALUE_HEADER};
+use super::{
+    compacting_path, compacting_value_path, log_path, now_millis, remove_stale_gens, retire,
+    value_path, CommandPos, History, KvStoreWriter, Superseded,
+};
+use crate::Result;
+
+/// Progress of the running compaction and a summary of the last one.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionStats {
+    /// Whether a compaction is running right now.
+    pub running: bool,
+    /// Bytes the running compaction has copied so far.
+    pub bytes_copied: u64,
+    /// Live bytes the running compaction has to copy in total.
+    pub bytes_total: u64,
+    /// Compactions completed since the store was opened.
+    pub runs: u64,
+    /// The most recently completed compaction.
+    pub last_run: Option<CompactionRun>,
+}
+
+/// A summary of one completed compaction.
+#[derive(Clone, Debug)]
+pub struct CompactionRun {
+    pub finished: SystemTime,
+    pub duration: Duration,
+    /// Size of the generation the live records were copied into.
+    pub bytes_written: u64,
+    /// How much smaller the log got.
+    pub bytes_reclaimed: u64,
+    /// Bytes of live values copied out of the value log files collected.
+    pub value_bytes_copied: u64,
+    /// How much smaller the value log got.
+    pub value_bytes_reclaimed: u64,
+}
+
+pub(super) enum Message {
+    Compact,
+    Shutdown,
+}
+
+/// Everything a compaction needs, shared by the background thread and
+/// `KvStore::compaction`.
+pub(super) struct Compactor {
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<RwLock<BTreeMap<Vec<u8>, CommandPos>>>,
+    pub(super) history: Arc<RwLock<History>>,
+    pub(super) safe_point: Arc<AtomicU64>,
+    pub(super) writer: Arc<Mutex<KvStoreWriter>>,
+    pub(super) stats: Mutex<CompactionStats>,
+    // Set by the writer when it queues a compaction, cleared once it ran.
+    pub(super) pending: Arc<AtomicBool>,
+    // Keeps a manual compaction from overlapping a background one.
+    pub(super) running: Mutex<()>,
+    // Generations snapshots still read from, which must outlive compaction.
+    pub(super) pins: Pins,
+    // Value log files collected but not deleted yet.
+    pub(super) collected: Collected,
+}
+
+/// The value log files a compaction collects.
+struct Collection {
+    gens: BTreeSet<u64>,
+    /// Their size in total.
+    size: u64,
+    /// Stale bytes in the files left alone.
+    stale: u64,
+}
+
+impl Compactor {
+    /// Copies every live record, and the past versions options say to keep,
+    /// into a new generation with a hint file, and deletes the generations
+    /// before it, unless a snapshot still reads from them.
+    ///
+    /// Values in the value log stay where they are, except in the value log
+    /// files that are mostly stale. Those are collected: the values still
+    /// needed are copied into a value log file of the new generation and the
+    /// files are deleted along with the old generations.
+    ///
+    /// The writer lock is only held to roll the writer onto a fresh
+    /// generation and to swap the index at the end. While records are being
+    /// copied, readers and writers carry on against the old generations.
+    pub(super) fn run(&self) -> Result<()> {
+        let _running = self.running.lock().unwrap();
+        let timer = Instant::now();
+
+        let (
+            compaction_gen,
+            mut snapshot,
+            mut kept,
+            retained,
+            gc_ratio,
+            uncompacted,
+            log_size,
+            values_stale,
+            last_seq,
+        ) = {
+            let mut writer = self.writer.lock().unwrap();
+            let compaction_gen = writer.gen + 1;
+            let retained = writer.options.retained_versions;
+
+            // Writes made during compaction must replay after the compacted
+            // records, so they go to the generation after it.
+            writer.roll(compaction_gen + 1)?;
+
+            let snapshot = self.index.read().unwrap().clone();
+            let kept: BTreeMap<_, Vec<_>> = self
+                .history
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|_| retained > 0)
+                .map(|(key, versions)| {
+                    let start = versions.len().saturating_sub(retained);
+
+                    (key.clone(), versions[start..].to_vec())
+                })
+                .collect();
+
+            (
+                compaction_gen,
+                snapshot,
+                kept,
+                retained,
+                writer.options.value_gc_ratio,
+                writer.uncompacted,
+                writer.log_size,
+                writer.values_stale,
+                writer.seq,
+            )
+        };
+
+        // Expired keys are dropped rather than copied, unless they are among
+        // the past versions being kept.
+        let now = now_millis();
+        let expired: Vec<_> = snapshot
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, &cmd_pos)| (key.clone(), cmd_pos))
+            .collect();
+
+        for (key, cmd_pos) in &expired {
+            snapshot.remove(key);
+
+            if retained > 0 {
+                let versions = kept.entry(key.clone()).or_default();
+
+                versions.push(Superseded {
+                    cmd_pos: *cmd_pos,
+                    removed: false,
+                });
+
+                if versions.len() > retained {
+                    versions.remove(0);
+                }
+            }
+        }
+
+        // Each key's past versions go before its latest one, so replaying the
+        // new generation rebuilds the history in order.
+        for (key, cmd_pos) in snapshot {
+            kept.entry(key).or_default().push(Superseded {
+                cmd_pos,
+                removed: false,
+            });
+        }
+
+        // An expired version reads as missing, so once nothing older is
+        // kept for its key it hides nothing either, and goes the way of the
+        // key rather than being copied by every compaction from then on.
+        for versions in kept.values_mut() {
+            let hiding_nothing = versions
+                .iter()
+                .take_while(|version| !version.removed && version.cmd_pos.is_expired(now))
+                .count();
+
+            versions.drain(..hiding_nothing);
+        }
+
+        let records: Vec<_> = kept
+            .into_iter()
+            .flat_map(|(key, versions)| versions.into_iter().map(move |v| (key.clone(), v)))
+            .collect();
+        let collection = self.collection(compaction_gen, &records, gc_ratio)?;
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+
+            stats.running = true;
+            stats.bytes_copied = 0;
+            stats.bytes_total = records
+                .iter()
+                .map(|(_, v)| v.cmd_pos.len + relocated_len(&collection, v.cmd_pos))
+                .sum();
+        }
+
+        let result = self.copy(compaction_gen, last_seq, records, &collection);
+
+        self.stats.lock().unwrap().running = false;
+
+        let (moved, position, value_position) = match result {
+            Ok(copied) => copied,
+            Err(e) => {
+                // The generation was not renamed into place, so nothing
+                // points into its value log file either.
+                let _ = fs::remove_file(compacting_path(&self.path, compaction_gen));
+                let _ = fs::remove_file(compacting_value_path(&self.path, compaction_gen));
+                let _ = fs::remove_file(value_path(&self.path, compaction_gen));
+
+                return Err(e);
+            }
+        };
+
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            let mut history = self.history.write().unwrap();
+
+            // Expired keys are retired by the position they were copied
+            // from, so this goes before the index is moved on. Keys written
+            // since are left alone.
+            for (key, old) in expired {
+                if index.get(&key) == Some(&old) {
+                    retire(&mut index, &mut history, &key);
+                }
+            }
+
+            // Keys written or removed during the copy already point past the
+            // compacted generation and keep their newer position.
+            for cmd_pos in index.values_mut() {
+                if let Some(&new) = moved.get(cmd_pos) {
+                    *cmd_pos = new;
+                }
+            }
+
+            // Past versions left in the old generations are gone with them.
+            history.retain(|_, versions| {
+                versions.retain_mut(|version| match moved.get(&version.cmd_pos) {
+                    Some(&new) => {
+                        version.cmd_pos = new;
+                        true
+                    }
+                    None => version.cmd_pos.gen > compaction_gen,
+                });
+
+                !versions.is_empty()
+            });
+
+            self.safe_point.store(compaction_gen, Ordering::SeqCst);
+
+            // Stale bytes counted before the roll were dropped by the copy.
+            // Ones counted since still have a stale copy on disk.
+            writer.uncompacted -= uncompacted;
+            writer.log_size = writer.log_size - log_size + position;
+
+            // The same goes for stale values, apart from the ones in value
+            // log files that were not collected.
+            writer.values_stale = writer.values_stale - values_stale + collection.stale;
+            writer.values_size = writer.values_size - collection.size + value_position;
+        }
+
+        {
+            let pins = self.pins.lock().unwrap();
+            let mut collected = self.collected.lock().unwrap();
+
+            if !collection.gens.is_empty() {
+                collected.insert(compaction_gen, collection.gens.into_iter().collect());
+            }
+
+            remove_stale_gens(&self.path, compaction_gen, &pins, &mut collected)?;
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+
+        stats.runs += 1;
+        stats.last_run = Some(CompactionRun {
+            finished: SystemTime::now(),
+            duration: timer.elapsed(),
+            bytes_written: position,
+            bytes_reclaimed: log_size.saturating_sub(position),
+            value_bytes_copied: value_position,
+            value_bytes_reclaimed: collection.size.saturating_sub(value_position),
+        });
+
+        Ok(())
+    }
+
+    /// Picks the value log files from before `compaction_gen` in which at
+    /// least `ratio` of the bytes are values none of `records` point to.
+    fn collection(
+        &self,
+        compaction_gen: u64,
+        records: &[(Vec<u8>, Superseded)],
+        ratio: f64,
+    ) -> Result<Collection> {
+        let mut live: BTreeMap<u64, u64> = BTreeMap::new();
+
+        for (_, version) in records {
+            if let Some(value_pos) = version.cmd_pos.value {
+                *live.entry(value_pos.gen).or_default() += value_pos.len;
+            }
+        }
+
+        // Files collected before are only waiting for snapshots to let go.
+        let collected: BTreeSet<u64> = self
+            .collected
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        let mut collection = Collection {
+            gens: BTreeSet::new(),
+            size: 0,
+            stale: 0,
+        };
+
+        for (gen, size) in value::sizes(&self.path)? {
+            if gen >= compaction_gen || collected.contains(&gen) {
+                continue;
+            }
+
+            let stale = size.saturating_sub(live.get(&gen).copied().unwrap_or(0));
+
+            if stale as f64 >= ratio * size as f64 {
+                collection.gens.insert(gen);
+                collection.size += size;
+            } else {
+                collection.stale += stale;
+            }
+        }
+
+        Ok(collection)
+    }
+
+    /// Writes `records` to `compaction_gen` in order, after a sequence mark
+    /// for `last_seq`, under a temporary name that is renamed into place once
+    /// it is synced, followed by its hint file.
+    ///
+    /// Values in the value log files being collected are copied into the new
+    /// generation's value log file first, the same way.
+    ///
+    /// Returns where each record ended up, and the bytes written to the new
+    /// generation and to its value log file.
+    #[allow(clippy::type_complexity)]
+    fn copy(
+        &self,
+        compaction_gen: u64,
+        last_seq: u64,
+        records: Vec<(Vec<u8>, Superseded)>,
+        collection: &Collection,
+    ) -> Result<(HashMap<CommandPos, CommandPos>, u64, u64)> {
+        let temp_path = compacting_path(&self.path, compaction_gen);
+        let mut compacted = BufWriter::new(File::create(&temp_path)?);
+        let mut readers = HashMap::new();
+        let mut value_readers = ValueReaders::new();
+        let mut values = None;
+        let mut moved = HashMap::with_capacity(records.len());
+        let mut hints = Vec::with_capacity(records.len());
+        let mark = record::encode_sequence_mark(last_seq);
+        let mut position = (FILE_HEADER.len() + mark.len()) as u64;
+        let mut value_position = VALUE_HEADER.len() as u64;
+        let mut copied = 0;
+
+        compacted.write_all(FILE_HEADER)?;
+        // The records with the highest sequence numbers may be among the
+        // ones dropped, so the mark keeps them from being handed out again.
+        compacted.write_all(&mark)?;
+
+        for (key, Superseded { cmd_pos, removed }) in records {
+            let value = match cmd_pos.value {
+                Some(value_pos) if collection.gens.contains(&value_pos.gen) => {
+                    let entry = value_readers.read_entry(&self.path, value_pos)?;
+                    let values = match &mut values {
+                        Some(values) => values,
+                        None => values.insert(self.create_values(compaction_gen)?),
+                    };
+
+                    values.write_all(&entry)?;
+
+                    let new_value_pos = ValuePos {
+                        gen: compaction_gen,
+                        pos: value_position,
+                        len: value_pos.len,
+                    };
+
+                    value_position += value_pos.len;
+                    copied += value_pos.len;
+
+                    // Only where the value is changes, which takes up the
+                    // same number of bytes.
+                    compacted.write_all(&record::encode_separate(
+                        &key,
+                        new_value_pos,
+                        cmd_pos.expires_at,
+                        cmd_pos.seq,
+                    ))?;
+
+                    Some(new_value_pos)
+                }
+                value => {
+                    let reader = match readers.entry(cmd_pos.gen) {
+                        hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                        hash_map::Entry::Vacant(entry) => {
+                            entry.insert(File::open(log_path(&self.path, cmd_pos.gen))?)
+                        }
+                    };
+
+                    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                    io::copy(&mut reader.take(cmd_pos.len), &mut compacted)?;
+
+                    value
+                }
+            };
+
+            let new_pos = CommandPos {
+                gen: compaction_gen,
+                pos: position,
+                value,
+                ..cmd_pos
+            };
+
+            moved.insert(cmd_pos, new_pos);
+            hints.push(Hint {
+                key,
+                pos: position,
+                len: cmd_pos.len,
+                expires_at: cmd_pos.expires_at,
+                seq: cmd_pos.seq,
+                removed,
+                value,
+            });
+            position += cmd_pos.len;
+            copied += cmd_pos.len;
+
+            self.stats.lock().unwrap().bytes_copied = copied;
+        }
+
+        // The new generation points into its value log file, so that has to
+        // be in place first.
+        if let Some(mut values) = values {
+            values.flush()?;
+            values.get_ref().sync_all()?;
+            fs::rename(
+                compacting_value_path(&self.path, compaction_gen),
+                value_path(&self.path, compaction_gen),
+            )?;
+        }
+
+        compacted.flush()?;
+        compacted.get_ref().sync_all()?;
+        fs::rename(&temp_path, log_path(&self.path, compaction_gen))?;
+
+        // Without hints the generation is replayed on open, which is slower
+        // but just as correct, so failing to write them fails nothing.
+        if let Err(e) = hint::write(&self.path, compaction_gen, position, last_seq, &hints) {
+            warn!(
+                "unable to write hints for generation {}: {}",
+                compaction_gen, e
+            );
+        }
+
+        Ok((
+            moved,
+            position - FILE_HEADER.len() as u64,
+            value_position - VALUE_HEADER.len() as u64,
+        ))
+    }
+
+    /// Creates the value log file for `compaction_gen` under a temporary
+    /// name.
+    fn create_values(&self, compaction_gen: u64) -> Result<BufWriter<File>> {
+        let file = File::create(compacting_value_path(&self.path, compaction_gen))?;
+        let mut values = BufWriter::new(file);
+
+        values.write_all(VALUE_HEADER)?;
+
+        Ok(values)
+    }
+}
+
+/// How many bytes of `cmd_pos`'s value a compaction with `collection` copies.
+fn relocated_len(collection: &Collection, cmd_pos: CommandPos) -> u64 {
+    match cmd_pos.value {
+        Some(value_pos) if collection.gens.contains(&value_pos.gen) => value_pos.len,
+        _ => 0,
+    }
+}
+
+/// The thread that runs compactions queued by the writer.
+///
+/// Dropping it waits for a running compaction to finish and stops the
+/// thread, so the log is settled by the time the last `KvStore` is gone.
+pub(super) struct BackgroundCompaction {
+    sender: Sender<Message>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundCompaction {
+    pub(super) fn spawn(
+        compactor: Arc<Compactor>,
+        sender: Sender<Message>,
+        receiver: Receiver<Message>,
+    ) -> Result<BackgroundCompaction> {
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Compact => {
+                            if let Err(e) = compactor.run() {
+                                error!("compaction failed: {}", e);
+                            }
+
+                            compactor.pending.store(false, Ordering::SeqCst);
+                        }
+                        Message::Shutdown => break,
+                    }
+                }
+            })?;
+
+        Ok(BackgroundCompaction {
+            sender,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for BackgroundCompaction {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Message::Shutdown);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::value::ValuePos;
+use super::{compacting_hint_path, hint_path};
+use crate::Result;
+
+/// Written at the start of every hint file.
+const HINT_HEADER: &[u8; 8] = b"KVSHNT\x00\x02";
+
+const REMOVED: u8 = 1;
+
+/// Set when the hint carries an expiry time.
+const EXPIRES: u8 = 2;
+
+/// Set when the record's value is kept in the value log, in which case where
+/// it is follows the expiry time.
+const SEPARATE: u8 = 4;
+
+/// Flags (u8), key length (u32), offset, length and sequence number (u64
+/// each), all little-endian. The expiry time, value position and key
+/// follow.
+const HINT_LEN: usize = 29;
+
+/// Where one record of a compacted generation is, with what replaying it
+/// would have learned, so that opening the store does not have to.
+pub(super) struct Hint {
+    pub(super) key: Vec<u8>,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+    pub(super) expires_at: Option<u64>,
+    pub(super) seq: u64,
+    pub(super) removed: bool,
+    pub(super) value: Option<ValuePos>,
+}
+
+/// The hints for a generation, covering its first `log_len` bytes, and the
+/// sequence number of the mark it starts with.
+pub(super) struct Hints {
+    pub(super) log_len: u64,
+    pub(super) last_seq: u64,
+    pub(super) hints: Vec<Hint>,
+}
+
+/// Writes the hint file for `gen`, which compaction has just written
+/// `log_len` bytes to, starting with a sequence mark for `last_seq`.
+///
+/// Like the generation itself, it is synced under a temporary name first, so
+/// a hint file is only ever found whole.
+pub(super) fn write(
+    path: &Path,
+    gen: u64,
+    log_len: u64,
+    last_seq: u64,
+    hints: &[Hint],
+) -> Result<()> {
+    let temp_path = compacting_hint_path(path, gen);
+    let mut file = File::create(&temp_path)?;
+
+    file.write_all(&encode(log_len, last_seq, hints))?;
+    file.sync_all()?;
+    fs::rename(&temp_path, hint_path(path, gen))?;
+
+    Ok(())
+}
+
+/// Reads the hint file for `gen`, if it has one. A file that fails its
+/// checksum is treated as missing, so the generation gets replayed.
+pub(super) fn read(path: &Path, gen: u64) -> Result<Option<Hints>> {
+    match fs::read(hint_path(path, gen)) {
+        Ok(bytes) => Ok(decode(&bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The header, the length of the log covered, the sequence mark, every hint
+/// and a checksum over all of it.
+fn encode(log_len: u64, last_seq: u64, hints: &[Hint]) -> Vec<u8> {
+    let mut bytes = HINT_HEADER.to_vec();
+
+    bytes.extend_from_slice(&log_len.to_le_bytes());
+    bytes.extend_from_slice(&last_seq.to_le_bytes());
+
+    for hint in hints {
+        let mut flags = 0;
+
+        if hint.removed {
+            flags |= REMOVED;
+        }
+
+        if hint.expires_at.is_some() {
+            flags |= EXPIRES;
+        }
+
+        if hint.value.is_some() {
+            flags |= SEPARATE;
+        }
+
+        bytes.push(flags);
+        bytes.extend_from_slice(&(hint.key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&hint.pos.to_le_bytes());
+        bytes.extend_from_slice(&hint.len.to_le_bytes());
+        bytes.extend_from_slice(&hint.seq.to_le_bytes());
+
+        if let Some(expires_at) = hint.expires_at {
+            bytes.extend_from_slice(&expires_at.to_le_bytes());
+        }
+
+        if let Some(value) = hint.value {
+            bytes.extend_from_slice(&value.encode());
+        }
+
+        bytes.extend_from_slice(&hint.key);
+    }
+
+    let checksum = crc32fast::hash(&bytes);
+
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Hints> {
+    let (body, checksum) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+
+    if !body.starts_with(HINT_HEADER)
+        || crc32fast::hash(body).to_le_bytes() != checksum
+        || body.len() < HINT_HEADER.len() + 16
+    {
+        return None;
+    }
+
+    let mut body = &body[HINT_HEADER.len()..];
+    let log_len = take_u64(&mut body)?;
+    let last_seq = take_u64(&mut body)?;
+    let mut hints = Vec::new();
+
+    while !body.is_empty() {
+        if body.len() < HINT_LEN {
+            return None;
+        }
+
+        let flags = body[0];
+        let key_len = u32::from_le_bytes([body[1], body[2], body[3], body[4]]) as usize;
+
+        body = &body[5..];
+
+        let pos = take_u64(&mut body)?;
+        let len = take_u64(&mut body)?;
+        let seq = take_u64(&mut body)?;
+        let expires_at = match flags & EXPIRES {
+            0 => None,
+            _ => Some(take_u64(&mut body)?),
+        };
+        let value = match flags & SEPARATE {
+            0 => None,
+            _ => Some(ValuePos {
+                gen: take_u64(&mut body)?,
+                pos: take_u64(&mut body)?,
+                len: take_u64(&mut body)?,
+            }),
+        };
+        let (key, rest) = body.split_at_checked(key_len)?;
+
+        body = rest;
+
+        hints.push(Hint {
+            key: key.to_vec(),
+            pos,
+            len,
+            expires_at,
+            seq,
+            removed: flags & REMOVED != 0,
+            value,
+        });
+    }
+
+    Some(Hints {
+        log_len,
+        last_seq,
+        hints,
+    })
+}
+
+/// Reads a little-endian `u64` off the front of `bytes`, if there is one.
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    let (number, rest) = bytes.split_first_chunk::<8>()?;
+
+    *bytes = rest;
+
+    Some(u64::from_le_bytes(*number))
+}
@@ -0,0 +1,1425 @@
+use std::cell::RefCell;
+use std::collections::{btree_map, BTreeMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use failure::Error;
+use log::{info, warn};
+use memmap2::Mmap;
+
+use self::compaction::{BackgroundCompaction, Compactor, Message};
+use self::namespace::Namespaces;
+use self::record::{Record, Value};
+use self::snapshot::Pin;
+use self::sync::{BackgroundSync, Syncer};
+use self::value::{ValuePos, ValueReaders};
+use super::batch::BatchOp;
+use super::{
+    add_to_counter, check_namespace, expires_at, now_millis, scan_bounds, CasOutcome, Durability,
+    KvsEngine, Version, WriteBatch,
+};
+use crate::{CorruptRecord, KeyNotFound, Result};
+
+mod compaction;
+mod hint;
+mod namespace;
+mod record;
+mod snapshot;
+mod sync;
+mod value;
+
+pub use self::compaction::{CompactionRun, CompactionStats};
+pub use self::snapshot::KvStoreSnapshot;
+
+/// The directory, inside a store's own, that holds one subdirectory per
+/// namespace.
+const NAMESPACES_DIR: &str = "namespaces";
+
+/// A cheap-to-clone handle to a log-structured store.
+///
+/// The log is split into numbered generation files (`1.log`, `2.log`, ...).
+/// Every clone shares the same index and writer. Reads only take the index
+/// lock in shared mode, so clones on different threads can read in parallel
+/// with each other and with appends to the log. Compaction runs on a
+/// background thread.
+///
+/// Each [namespace](KvsEngine::namespace) is a separate log in a
+/// subdirectory, compacted on its own.
+///
+/// Values over the [`value_threshold`](KvStoreOptions::value_threshold) are
+/// kept out of the log, in value log files (`1.vlog`, ...), and the log only
+/// records where they are. Compaction copies them only out of value log
+/// files that are mostly garbage.
+pub struct KvStore {
+    path: Arc<PathBuf>,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, CommandPos>>>,
+    history: Arc<RwLock<History>>,
+    // Oldest generation still referenced by the index. Readers close their
+    // handles to anything older once compaction has deleted it.
+    safe_point: Arc<AtomicU64>,
+    // The generation being appended to. Every one before it is immutable.
+    active_gen: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+    // Memory maps of immutable generations, when reads go through them.
+    maps: RefCell<BTreeMap<u64, Mmap>>,
+    mmap_reads: bool,
+    value_readers: RefCell<ValueReaders>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    syncer: Arc<Syncer>,
+    compactor: Arc<Compactor>,
+    background: Arc<BackgroundCompaction>,
+    background_sync: Option<Arc<BackgroundSync>>,
+    // `None` only for the stores `Namespaces` keeps for itself.
+    namespaces: Option<Arc<Namespaces>>,
+}
+
+/// Where a record lives: its generation, byte offset and length, along with
+/// the expiry time of its key, its sequence number and where its value is if
+/// the value log holds it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+    seq: u64,
+    value: Option<ValuePos>,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A version of a key that is no longer its latest, oldest first. A key that
+/// was removed, or has expired, ends in the version that did so.
+///
+/// Lock it after the index when holding both.
+type History = BTreeMap<Vec<u8>, Vec<Superseded>>;
+
+#[derive(Clone, Copy, Debug)]
+struct Superseded {
+    cmd_pos: CommandPos,
+    removed: bool,
+}
+
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, CommandPos>>>,
+    history: Arc<RwLock<History>>,
+    options: KvStoreOptions,
+    compaction: Sender<Message>,
+    compaction_pending: Arc<AtomicBool>,
+    syncer: Arc<Syncer>,
+    active_gen: Arc<AtomicU64>,
+    gen: u64,
+    file: File,
+    position: u64,
+    // The value log file of the generation being appended to, once a value
+    // has gone into it, and its length.
+    value_file: Option<File>,
+    value_position: u64,
+    // The sequence number of the last write.
+    seq: u64,
+    // Bytes in the log taken up by overwritten and removed records.
+    uncompacted: u64,
+    // Bytes across every generation still on disk.
+    log_size: u64,
+    // The same for the value log: bytes of values the index no longer
+    // points to, and bytes across every value log file.
+    values_stale: u64,
+    values_size: u64,
+}
+
+/// Options for opening a [`KvStore`].
+///
+/// ```
+/// use kvs::KvStoreOptions;
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStoreOptions::new()
+///     .compaction_threshold(64 * 1024)
+///     .compaction_ratio(0.5)
+///     .open(temp_dir.path())
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    compaction_ratio: Option<f64>,
+    retained_versions: usize,
+    durability: Durability,
+    mmap_reads: bool,
+    value_threshold: Option<u64>,
+    value_gc_ratio: f64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: None,
+            retained_versions: 0,
+            durability: Durability::Never,
+            mmap_reads: false,
+            value_threshold: None,
+            value_gc_ratio: 0.5,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Compacts once this many bytes of the log are stale. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Also compacts once stale bytes make up this fraction of the log,
+    /// even if the threshold has not been reached.
+    pub fn compaction_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Keeps up to this many past versions of each key through compaction,
+    /// for [`get_at`](KvsEngine::get_at) and [`history`](KvsEngine::history).
+    /// Defaults to 0: past versions last until the next compaction.
+    pub fn retained_versions(mut self, versions: usize) -> KvStoreOptions {
+        self.retained_versions = versions;
+        self
+    }
+
+    /// When writes are synced to disk. Defaults to [`Durability::Never`].
+    ///
+    /// With [`Durability::EveryWrite`], writers that arrive while a sync is
+    /// running share the next one rather than queueing up a sync each.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Reads records in generations the writer has moved past through
+    /// memory maps of them, rather than by seeking and reading a file.
+    /// Records in the generation being appended to are still read from the
+    /// file. Defaults to off.
+    pub fn mmap_reads(mut self, enabled: bool) -> KvStoreOptions {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// Keeps values longer than this many bytes in the value log rather than
+    /// in the log itself, so compaction does not copy them every time it
+    /// runs. Defaults to off: every value stays in the log.
+    pub fn value_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.value_threshold = Some(bytes);
+        self
+    }
+
+    /// Collects a value log file once this fraction of it is values the
+    /// index no longer points to, copying the rest out of it during the next
+    /// compaction. Once stale values reach the compaction threshold and this
+    /// fraction of the value log, a compaction is queued for them. Defaults
+    /// to 0.5.
+    pub fn value_gc_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.value_gc_ratio = ratio;
+        self
+    }
+
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self.clone())
+    }
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> KvStore {
+        KvStore {
+            path: Arc::clone(&self.path),
+            index: Arc::clone(&self.index),
+            history: Arc::clone(&self.history),
+            safe_point: Arc::clone(&self.safe_point),
+            active_gen: Arc::clone(&self.active_gen),
+            readers: RefCell::new(BTreeMap::new()),
+            maps: RefCell::new(BTreeMap::new()),
+            mmap_reads: self.mmap_reads,
+            value_readers: RefCell::new(ValueReaders::new()),
+            writer: Arc::clone(&self.writer),
+            syncer: Arc::clone(&self.syncer),
+            compactor: Arc::clone(&self.compactor),
+            background: Arc::clone(&self.background),
+            background_sync: self.background_sync.clone(),
+            namespaces: self.namespaces.clone(),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let index = self.index.read().unwrap();
+
+        match index.get(&key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(now_millis()) => Ok(Some(self.read(cmd_pos)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+
+        match self.index.read().unwrap().get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now) => Ok(cmd_pos
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(Error::from(KeyNotFound)),
+        }
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+
+        let now = now_millis();
+        // The values are read once the index lock is released, so a long
+        // scan does not hold up writers. The pin keeps compaction from
+        // deleting the generations they are in meanwhile.
+        let (found, _pin) = {
+            let index = self.index.read().unwrap();
+            let found: Vec<(Vec<u8>, CommandPos)> = index
+                .range(bounds)
+                .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(key, &cmd_pos)| (key.clone(), cmd_pos))
+                .collect();
+
+            (found, self.pin())
+        };
+
+        found
+            .into_iter()
+            .map(|(key, cmd_pos)| Ok((key, self.read(cmd_pos)?)))
+            .collect()
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let seq = self.writer.lock().unwrap().set(key, value, None)?;
+
+        self.syncer.commit(seq)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let seq = self
+            .writer
+            .lock()
+            .unwrap()
+            .set(key, value, Some(expires_at(ttl)))?;
+
+        self.syncer.commit(seq)
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let seq = self.writer.lock().unwrap().remove(key)?;
+
+        self.syncer.commit(seq)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let seq = self.writer.lock().unwrap().write_batch(batch)?;
+
+        self.syncer.commit(seq)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        // Holding the writer keeps anyone else from changing the key between
+        // the comparison and the write.
+        let mut writer = self.writer.lock().unwrap();
+        let current = self.get(key.clone())?;
+
+        if current != expected {
+            return Ok(CasOutcome::Conflict { current });
+        }
+
+        let seq = match new {
+            Some(value) => writer.set(key, value, None)?,
+            None if current.is_some() => writer.remove(key)?,
+            None => return Ok(CasOutcome::Swapped),
+        };
+
+        // Other writers can go ahead while this one waits for its sync.
+        drop(writer);
+        self.syncer.commit(seq)?;
+
+        Ok(CasOutcome::Swapped)
+    }
+
+    fn get_at(&self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        let index = self.index.read().unwrap();
+        let history = self.history.read().unwrap();
+        let now = now_millis();
+
+        let version = match index.get(&key) {
+            Some(&cmd_pos) if cmd_pos.seq <= seq => Some(Superseded {
+                cmd_pos,
+                removed: false,
+            }),
+            _ => history.get(&key).and_then(|versions| {
+                versions
+                    .iter()
+                    .rev()
+                    .find(|version| version.cmd_pos.seq <= seq)
+                    .copied()
+            }),
+        };
+
+        match version {
+            Some(version) if !version.removed && !version.cmd_pos.is_expired(now) => {
+                Ok(Some(self.read(version.cmd_pos)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn history(&self, key: Vec<u8>, limit: Option<usize>) -> Result<Vec<Version>> {
+        let index = self.index.read().unwrap();
+        let history = self.history.read().unwrap();
+        let now = now_millis();
+        let current = index.get(&key).map(|&cmd_pos| Superseded {
+            cmd_pos,
+            removed: false,
+        });
+        let past = history.get(&key).into_iter().flatten().rev().copied();
+
+        current
+            .into_iter()
+            .chain(past)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|version| {
+                let value = match version.removed || version.cmd_pos.is_expired(now) {
+                    true => None,
+                    false => Some(self.read(version.cmd_pos)?),
+                };
+
+                Ok(Version {
+                    seq: version.cmd_pos.seq,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let index = self.index.read().unwrap();
+
+        Ok(KvStoreSnapshot::new(
+            Arc::clone(&self.path),
+            &index,
+            now_millis(),
+            self.pin(),
+        ))
+    }
+
+    fn namespace(&self, name: &str) -> Result<KvStore> {
+        check_namespace(name)?;
+
+        self.registry().open(name)
+    }
+
+    fn existing_namespace(&self, name: &str) -> Result<Option<KvStore>> {
+        check_namespace(name)?;
+
+        self.registry().find(name)
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<bool> {
+        check_namespace(name)?;
+
+        self.registry().remove(name)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.registry().names()
+    }
+
+    fn commit_batch(
+        &self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        // Every write goes through the writer, so holding it while checking
+        // the watched values stands in for comparing per-key versions.
+        let mut writer = self.writer.lock().unwrap();
+
+        for (key, expected) in watched {
+            if self.get(key)? != expected {
+                return Ok(false);
+            }
+        }
+
+        let seq = writer.write_batch(batch)?;
+
+        drop(writer);
+        self.syncer.commit(seq)?;
+
+        Ok(true)
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut writer = self.writer.lock().unwrap();
+        let (current, expires_at) = match self.index.read().unwrap().get(&key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(now_millis()) => {
+                (Some(self.read(cmd_pos)?), cmd_pos.expires_at)
+            }
+            _ => (None, None),
+        };
+        let counter = add_to_counter(current.as_deref(), delta)?;
+
+        let seq = writer.set(key, counter.to_string().into_bytes(), expires_at)?;
+
+        drop(writer);
+        self.syncer.commit(seq)?;
+
+        Ok(counter)
+    }
+}
+
+impl KvStoreWriter {
+    /// Logs a write and returns its sequence number, for the caller to wait
+    /// on once it has let go of the writer. The same goes for `remove` and
+    /// `write_batch`.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        let cmd_pos = self.log(&key, Some(&value), expires_at)?;
+        let mut index = self.index.write().unwrap();
+        let mut history = self.history.write().unwrap();
+
+        self.values_stale += value_len(&index, &key);
+        self.uncompacted += apply_version(&mut index, &mut history, key, cmd_pos, false);
+
+        drop(history);
+        drop(index);
+
+        self.maybe_compact()?;
+
+        Ok(cmd_pos.seq)
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<u64> {
+        let expired = match self.index.read().unwrap().get(&key) {
+            Some(cmd_pos) => cmd_pos.is_expired(now_millis()),
+            None => return Err(Error::from(KeyNotFound)),
+        };
+
+        // An expired record already reads as removed once the log is
+        // replayed, so it only has to leave the index.
+        if expired {
+            let mut index = self.index.write().unwrap();
+            let mut history = self.history.write().unwrap();
+
+            self.values_stale += value_len(&index, &key);
+            self.uncompacted += retire(&mut index, &mut history, &key);
+
+            return Err(Error::from(KeyNotFound));
+        }
+
+        let cmd_pos = self.log(&key, None, None)?;
+        let mut index = self.index.write().unwrap();
+        let mut history = self.history.write().unwrap();
+
+        self.values_stale += value_len(&index, &key);
+        self.uncompacted += apply_version(&mut index, &mut history, key, cmd_pos, true);
+
+        drop(history);
+        drop(index);
+
+        self.maybe_compact()?;
+
+        Ok(cmd_pos.seq)
+    }
+
+    /// Appends the batch as a single record and updates the index for all of
+    /// it under one lock, so readers never see part of it.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(self.seq);
+        }
+
+        let first_seq = self.seq + 1;
+        let mut records = Vec::with_capacity(batch.len());
+        let mut values = Vec::with_capacity(batch.len());
+
+        for (op, seq) in batch.ops.iter().zip(first_seq..) {
+            let (record, value) = match op {
+                BatchOp::Set { key, value } => self.encode(key, Some(value), None, seq)?,
+                BatchOp::Remove { key } => self.encode(key, None, None, seq)?,
+            };
+
+            records.push(record);
+            values.push(value);
+        }
+
+        let batch_record = record::encode_batch(&records);
+
+        self.file.write_all(&batch_record)?;
+        self.seq += records.len() as u64;
+        self.syncer.written(self.seq);
+
+        let mut index = self.index.write().unwrap();
+        let mut history = self.history.write().unwrap();
+        let mut pos = self.position + record::HEADER_LEN as u64;
+
+        self.uncompacted += record::HEADER_LEN as u64;
+
+        for (((op, record), value), seq) in batch
+            .ops
+            .into_iter()
+            .zip(records)
+            .zip(values)
+            .zip(first_seq..)
+        {
+            let cmd_pos = CommandPos {
+                gen: self.gen,
+                pos,
+                len: record.len() as u64,
+                expires_at: None,
+                seq,
+                value,
+            };
+
+            pos += cmd_pos.len;
+
+            let (key, removed) = match op {
+                BatchOp::Set { key, .. } => (key, false),
+                BatchOp::Remove { key } => (key, true),
+            };
+
+            self.values_stale += value_len(&index, &key);
+            self.uncompacted += apply_version(&mut index, &mut history, key, cmd_pos, removed);
+        }
+
+        drop(history);
+        drop(index);
+
+        self.position += batch_record.len() as u64;
+        self.log_size += batch_record.len() as u64;
+
+        self.maybe_compact()?;
+
+        Ok(self.seq)
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        let over_threshold = self.uncompacted >= self.options.compaction_threshold;
+        let over_ratio = match self.options.compaction_ratio {
+            Some(ratio) => self.uncompacted as f64 >= ratio * self.log_size as f64,
+            None => false,
+        };
+
+        // Stale values barely show in the log, which only points to them, so
+        // they queue a compaction on their own.
+        let values_over = self.values_stale >= self.options.compaction_threshold
+            && self.values_stale as f64 >= self.options.value_gc_ratio * self.values_size as f64;
+
+        if ((self.uncompacted > 0 && (over_threshold || over_ratio)) || values_over)
+            && !self.compaction_pending.swap(true, Ordering::SeqCst)
+        {
+            self.compaction.send(Message::Compact)?;
+        }
+
+        Ok(())
+    }
+
+    fn log(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<CommandPos> {
+        let seq = self.seq + 1;
+        let (record, value) = self.encode(key, value, expires_at, seq)?;
+
+        self.file.write_all(&record)?;
+        self.seq = seq;
+        self.syncer.written(seq);
+
+        let cmd_pos = CommandPos {
+            gen: self.gen,
+            pos: self.position,
+            len: record.len() as u64,
+            expires_at,
+            seq,
+            value,
+        };
+
+        self.position += cmd_pos.len;
+        self.log_size += cmd_pos.len;
+
+        Ok(cmd_pos)
+    }
+
+    /// Encodes a record, first appending its value to the value log if it is
+    /// over the threshold. Returns where the value went, if it did.
+    fn encode(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+        seq: u64,
+    ) -> Result<(Vec<u8>, Option<ValuePos>)> {
+        match (value, self.options.value_threshold) {
+            (Some(value), Some(threshold)) if value.len() as u64 > threshold => {
+                let value_pos = self.log_value(key, value)?;
+
+                Ok((
+                    record::encode_separate(key, value_pos, expires_at, seq),
+                    Some(value_pos),
+                ))
+            }
+            _ => Ok((record::encode(key, value, expires_at, seq), None)),
+        }
+    }
+
+    /// Appends a value to the value log file of the current generation,
+    /// creating it if this is its first value.
+    fn log_value(&mut self, key: &[u8], value: &[u8]) -> Result<ValuePos> {
+        if self.value_file.is_none() {
+            let (file, position) = value::new_value_file(&self.path, self.gen)?;
+
+            self.syncer.values(file.try_clone()?);
+            self.value_file = Some(file);
+            self.value_position = position;
+        }
+
+        let entry = value::encode(key, value);
+        let value_pos = ValuePos {
+            gen: self.gen,
+            pos: self.value_position,
+            len: entry.len() as u64,
+        };
+
+        self.value_file.as_mut().unwrap().write_all(&entry)?;
+        self.value_position += value_pos.len;
+        self.values_size += value_pos.len;
+
+        Ok(value_pos)
+    }
+
+    /// Moves the writer onto a new, empty generation.
+    fn roll(&mut self, gen: u64) -> Result<()> {
+        let (file, position) = new_log_file(&self.path, gen)?;
+
+        self.syncer.roll(
+            &self.file,
+            self.value_file.as_ref(),
+            file.try_clone()?,
+            self.seq,
+        )?;
+        self.active_gen.store(gen, Ordering::SeqCst);
+
+        self.file = file;
+        self.gen = gen;
+        self.position = position;
+        self.value_file = None;
+
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Rewrites the log so that it only holds the live value of each key,
+    /// without waiting for the stale-bytes threshold.
+    ///
+    /// Runs on the calling thread and returns once the old generations are
+    /// gone.
+    pub fn compaction(&self) -> Result<()> {
+        self.compactor.run()
+    }
+
+    /// Returns the progress of the running compaction and a summary of the
+    /// last completed one.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compactor.stats.lock().unwrap().clone()
+    }
+
+    /// Opens the store with the default [`KvStoreOptions`].
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::default().open(path)
+    }
+
+    fn open_with(path: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        let namespaces = Namespaces::new(path.join(NAMESPACES_DIR), options.clone());
+
+        KvStore::open_log(path, options, Some(Arc::new(namespaces)))
+    }
+
+    /// Opens the log in `path`, leaving out the namespaces under it.
+    fn open_log(
+        path: PathBuf,
+        options: KvStoreOptions,
+        namespaces: Option<Arc<Namespaces>>,
+    ) -> Result<KvStore> {
+        let path = Arc::new(path);
+
+        fs::create_dir_all(path.as_path())?;
+
+        clean_up(&path)?;
+
+        let gens = sorted_gens(&path)?;
+
+        for &gen in &gens {
+            if !record::is_binary(&mut File::open(log_path(&path, gen))?)? {
+                migrate(&path, gen)?;
+            }
+        }
+
+        let mut index = BTreeMap::new();
+        let mut history = BTreeMap::new();
+        let mut uncompacted = 0;
+        let mut log_size = 0;
+        let mut seq = 0;
+
+        for &gen in &gens {
+            let loaded = load(&path, gen, &mut index, &mut history)?;
+
+            uncompacted += loaded.stale;
+            log_size += loaded.size;
+            seq = seq.max(loaded.last_seq);
+        }
+
+        let gen = gens.last().copied().unwrap_or(1);
+        let safe_point = gens.first().copied().unwrap_or(gen);
+
+        // Values only the history points to count as stale, like the records
+        // they belong to.
+        let values_size = value::sizes(&path)?.values().sum::<u64>();
+        let values_live = index.values().map(value_len_of).sum::<u64>();
+
+        let (file, position) = new_log_file(&path, gen)?;
+        let syncer = Arc::new(Syncer::new(options.durability, file.try_clone()?, seq));
+        let background_sync = BackgroundSync::spawn(Arc::clone(&syncer))?;
+
+        let index = Arc::new(RwLock::new(index));
+        let history = Arc::new(RwLock::new(history));
+        let safe_point = Arc::new(AtomicU64::new(safe_point));
+        let active_gen = Arc::new(AtomicU64::new(gen));
+        let mmap_reads = options.mmap_reads;
+        let pending = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            history: Arc::clone(&history),
+            options,
+            compaction: sender.clone(),
+            compaction_pending: Arc::clone(&pending),
+            syncer: Arc::clone(&syncer),
+            active_gen: Arc::clone(&active_gen),
+            gen,
+            file,
+            position,
+            value_file: None,
+            value_position: 0,
+            seq,
+            uncompacted,
+            log_size,
+            values_stale: values_size.saturating_sub(values_live),
+            values_size,
+        }));
+
+        let compactor = Arc::new(Compactor {
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            history: Arc::clone(&history),
+            safe_point: Arc::clone(&safe_point),
+            writer: Arc::clone(&writer),
+            stats: Mutex::new(CompactionStats::default()),
+            pending,
+            running: Mutex::new(()),
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+            collected: Arc::new(Mutex::new(BTreeMap::new())),
+        });
+
+        let background = BackgroundCompaction::spawn(Arc::clone(&compactor), sender, receiver)?;
+
+        Ok(KvStore {
+            path,
+            index,
+            history,
+            safe_point,
+            active_gen,
+            readers: RefCell::new(BTreeMap::new()),
+            maps: RefCell::new(BTreeMap::new()),
+            mmap_reads,
+            value_readers: RefCell::new(ValueReaders::new()),
+            writer,
+            syncer,
+            compactor,
+            background: Arc::new(background),
+            background_sync: background_sync.map(Arc::new),
+            namespaces,
+        })
+    }
+
+    fn registry(&self) -> &Arc<Namespaces> {
+        self.namespaces
+            .as_ref()
+            .expect("only stores kept by `Namespaces` have no registry")
+    }
+
+    /// Pins the generations the index refers to. The caller holds the index
+    /// lock.
+    fn pin(&self) -> Pin {
+        Pin::new(
+            Arc::clone(&self.path),
+            Arc::clone(&self.safe_point),
+            Arc::clone(&self.compactor.pins),
+            Arc::clone(&self.compactor.collected),
+        )
+    }
+
+    /// Reads the value of a record. The caller holds the index or history
+    /// lock that led to it, or a [`Pin`] taken under it, so compaction
+    /// cannot delete its generation, or the value log file its value is in.
+    fn read(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+
+        if let Some(value_pos) = cmd_pos.value {
+            let mut value_readers = self.value_readers.borrow_mut();
+
+            value_readers.close_stale(safe_point);
+
+            return value_readers.read(&self.path, value_pos);
+        }
+
+        let mut readers = self.readers.borrow_mut();
+        let mut maps = self.maps.borrow_mut();
+
+        close_before(&mut readers, safe_point);
+        close_before(&mut maps, safe_point);
+
+        if self.mmap_reads && cmd_pos.gen < self.active_gen.load(Ordering::SeqCst) {
+            return read_mapped(&mut maps, &self.path, cmd_pos);
+        }
+
+        read_record(&mut readers, &self.path, cmd_pos)
+    }
+}
+
+/// Drops the handles to generations before `safe_point`, which compaction
+/// has deleted.
+fn close_before<T>(handles: &mut BTreeMap<u64, T>, safe_point: u64) {
+    while let Some(entry) = handles.first_entry() {
+        if *entry.key() >= safe_point {
+            break;
+        }
+
+        entry.remove();
+    }
+}
+
+/// Reads the value of the record at `cmd_pos` out of a memory map of its
+/// generation, mapping it in `maps` if it is not mapped yet. The generation
+/// must be one the writer has moved past.
+fn read_mapped(
+    maps: &mut BTreeMap<u64, Mmap>,
+    path: &Path,
+    cmd_pos: CommandPos,
+) -> Result<Vec<u8>> {
+    let map = match maps.entry(cmd_pos.gen) {
+        btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        btree_map::Entry::Vacant(entry) => {
+            let file = File::open(log_path(path, cmd_pos.gen))?;
+
+            // SAFETY: nothing writes to a generation once the writer has
+            // moved past it, and deleting it after compaction leaves
+            // existing maps of it intact.
+            entry.insert(unsafe { Mmap::map(&file)? })
+        }
+    };
+
+    let start = cmd_pos.pos as usize;
+    let record = map
+        .get(start..start + cmd_pos.len as usize)
+        .ok_or(CorruptRecord {
+            gen: cmd_pos.gen,
+            offset: cmd_pos.pos,
+        })?;
+
+    record_value(record::decode(record, cmd_pos.gen, cmd_pos.pos)?, cmd_pos)
+}
+
+/// Reads the value of the record at `cmd_pos`, opening its generation in
+/// `readers` if it is not open yet.
+fn read_record(
+    readers: &mut BTreeMap<u64, BufReader<File>>,
+    path: &Path,
+    cmd_pos: CommandPos,
+) -> Result<Vec<u8>> {
+    let reader = match readers.entry(cmd_pos.gen) {
+        btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        btree_map::Entry::Vacant(entry) => {
+            let file = File::open(log_path(path, cmd_pos.gen))?;
+
+            entry.insert(BufReader::new(file))
+        }
+    };
+
+    let mut record = vec![0; cmd_pos.len as usize];
+
+    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    reader.read_exact(&mut record)?;
+
+    record_value(record::decode(&record, cmd_pos.gen, cmd_pos.pos)?, cmd_pos)
+}
+
+/// Reads the value of the record at `cmd_pos`, out of the value log if that
+/// is where it is.
+fn read_value(
+    readers: &mut BTreeMap<u64, BufReader<File>>,
+    value_readers: &mut ValueReaders,
+    path: &Path,
+    cmd_pos: CommandPos,
+) -> Result<Vec<u8>> {
+    match cmd_pos.value {
+        Some(value_pos) => value_readers.read(path, value_pos),
+        None => read_record(readers, path, cmd_pos),
+    }
+}
+
+/// The value the record read from `cmd_pos` holds.
+fn record_value(record: Record, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+    match record.value {
+        Some(Value::Inline(value)) => Ok(value),
+        // The index knows which values are in the value log and reads them
+        // from there, so this record is not the one it expected.
+        Some(Value::Separate(_)) => Err(Error::from(CorruptRecord {
+            gen: cmd_pos.gen,
+            offset: cmd_pos.pos,
+        })),
+        None => Err(Error::from(KeyNotFound)),
+    }
+}
+
+/// What replaying a generation found.
+struct Loaded {
+    /// Record bytes made stale by later records.
+    stale: u64,
+    /// Record bytes in total.
+    size: u64,
+    /// The highest sequence number in it.
+    last_seq: u64,
+}
+
+/// Replays one generation into `index` and `history`. Keys that have expired
+/// are left out of the index, like removed ones.
+///
+/// The part of a compacted generation its hint file covers is loaded from
+/// the hints instead, and only records appended after it are read.
+///
+/// A record cut short at the end of a generation is what a crash in the
+/// middle of a write leaves behind, so it is logged and truncated away. A
+/// bad record with more records after it is reported as corruption.
+fn load(
+    path: &Path,
+    gen: u64,
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
+    history: &mut History,
+) -> Result<Loaded> {
+    let file = File::open(log_path(path, gen))?;
+    let file_len = file.metadata()?.len();
+    let header_len = record::FILE_HEADER.len() as u64;
+
+    // Empty or with a partial header: nothing was written to it yet.
+    if file_len < header_len {
+        return Ok(Loaded {
+            stale: 0,
+            size: 0,
+            last_seq: 0,
+        });
+    }
+
+    let mut reader = BufReader::new(file);
+    let mut position = header_len;
+    let mut uncompacted = 0;
+    let mut last_seq = 0;
+    let now = now_millis();
+
+    match hint::read(path, gen)? {
+        Some(hints) if (header_len..=file_len).contains(&hints.log_len) => {
+            for hint in hints.hints {
+                let cmd_pos = CommandPos {
+                    gen,
+                    pos: hint.pos,
+                    len: hint.len,
+                    expires_at: hint.expires_at,
+                    seq: hint.seq,
+                    value: hint.value,
+                };
+
+                last_seq = last_seq.max(hint.seq);
+                uncompacted += apply_record(index, history, hint.key, cmd_pos, hint.removed, now);
+            }
+
+            last_seq = last_seq.max(hints.last_seq);
+            position = hints.log_len;
+        }
+        Some(_) => warn!("ignoring hints that do not match generation {}", gen),
+        None => {}
+    }
+
+    reader.seek(SeekFrom::Start(position))?;
+
+    while position < file_len {
+        let remaining = file_len - position;
+        let (entries, len) = match record::read_next(&mut reader, gen, position, remaining)? {
+            Some(next) => next,
+            None => {
+                warn!(
+                    "truncating torn record at offset {} of generation {}",
+                    position, gen
+                );
+
+                OpenOptions::new()
+                    .write(true)
+                    .open(log_path(path, gen))?
+                    .set_len(position)?;
+
+                break;
+            }
+        };
+
+        // A batch header belongs to none of the records inside it.
+        uncompacted += len - entries.iter().map(|entry| entry.len).sum::<u64>();
+
+        for entry in entries {
+            let record = entry.record;
+
+            if record.mark {
+                last_seq = last_seq.max(record.seq);
+
+                continue;
+            }
+
+            let value = match record.value {
+                Some(Value::Separate(value_pos)) => Some(value_pos),
+                _ => None,
+            };
+            let cmd_pos = CommandPos {
+                gen,
+                pos: position + entry.offset,
+                len: entry.len,
+                expires_at: record.expires_at,
+                seq: record.seq,
+                value,
+            };
+            let removed = record.value.is_none();
+
+            last_seq = last_seq.max(record.seq);
+            uncompacted += apply_record(index, history, record.key, cmd_pos, removed, now);
+        }
+
+        position += len;
+    }
+
+    Ok(Loaded {
+        stale: uncompacted,
+        size: position - header_len,
+        last_seq,
+    })
+}
+
+/// Applies a record found while loading the log. One that has expired by
+/// `now` goes straight into `history`. Returns how many record bytes that
+/// made stale.
+fn apply_record(
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
+    history: &mut History,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+    removed: bool,
+    now: u64,
+) -> u64 {
+    if removed || !cmd_pos.is_expired(now) {
+        return apply_version(index, history, key, cmd_pos, removed);
+    }
+
+    let stale = retire(index, history, &key) + cmd_pos.len;
+
+    push_version(history, key, cmd_pos, false);
+
+    stale
+}
+
+/// Makes `cmd_pos` the latest version of `key`, or records that `key` was
+/// removed there, and moves the version it replaces into `history`. Returns
+/// how many record bytes that made stale.
+fn apply_version(
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
+    history: &mut History,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+    removed: bool,
+) -> u64 {
+    let mut stale = retire(index, history, &key);
+
+    if removed {
+        // The remove record itself is stale as soon as it is written.
+        stale += cmd_pos.len;
+        push_version(history, key, cmd_pos, true);
+    } else {
+        index.insert(key, cmd_pos);
+    }
+
+    stale
+}
+
+/// Moves the latest version of `key`, if any, out of the index and into
+/// `history`, and returns its length.
+fn retire(index: &mut BTreeMap<Vec<u8>, CommandPos>, history: &mut History, key: &[u8]) -> u64 {
+    match index.remove(key) {
+        Some(old) => {
+            push_version(history, key.to_vec(), old, false);
+
+            old.len
+        }
+        None => 0,
+    }
+}
+
+/// How many bytes of the value log the latest version of `key` takes up.
+fn value_len(index: &BTreeMap<Vec<u8>, CommandPos>, key: &[u8]) -> u64 {
+    index.get(key).map_or(0, value_len_of)
+}
+
+fn value_len_of(cmd_pos: &CommandPos) -> u64 {
+    cmd_pos.value.map_or(0, |value_pos| value_pos.len)
+}
+
+fn push_version(history: &mut History, key: Vec<u8>, cmd_pos: CommandPos, removed: bool) {
+    history
+        .entry(key)
+        .or_default()
+        .push(Superseded { cmd_pos, removed });
+}
+
+/// Rewrites a generation of newline-delimited JSON records in the binary
+/// format.
+///
+/// Like compaction, the new file is synced under a temporary name before it
+/// replaces the old one.
+fn migrate(path: &Path, gen: u64) -> Result<()> {
+    let mut reader = BufReader::new(File::open(log_path(path, gen))?);
+    let temp_path = migrating_path(path, gen);
+    let mut migrated = BufWriter::new(File::create(&temp_path)?);
+    let mut position = 0;
+    let mut line = Vec::new();
+
+    migrated.write_all(record::FILE_HEADER)?;
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let record = match record::decode_json(&line, gen, position) {
+            Ok(record) => record,
+            Err(e) => {
+                if !reader.fill_buf()?.is_empty() {
+                    return Err(e);
+                }
+
+                warn!(
+                    "dropping torn record at offset {} of generation {}",
+                    position, gen
+                );
+
+                break;
+            }
+        };
+
+        // Records from before the binary format always hold their value.
+        let value = match &record.value {
+            Some(Value::Inline(value)) => Some(&value[..]),
+            _ => None,
+        };
+
+        migrated.write_all(&record::encode(&record.key, value, None, record.seq))?;
+
+        position += line.len() as u64;
+
+        line.clear();
+    }
+
+    migrated.flush()?;
+    migrated.get_ref().sync_all()?;
+    fs::rename(&temp_path, log_path(path, gen))?;
+
+    info!("migrated generation {} to the binary format", gen);
+
+    Ok(())
+}
+
+/// Removes what an interrupted compaction left behind and moves a log from
+/// the single-file layout into the first generation.
+fn clean_up(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+
+        let extension = entry_path.extension();
+
+        if extension == Some(OsStr::new("compacting")) || extension == Some(OsStr::new("migrating"))
+        {
+            fs::remove_file(&entry_path)?;
+        }
+    }
+
+    let next_log = path.join("next_log");
+    let current_log = path.join("current_log");
+
+    if next_log.exists() {
+        fs::remove_file(&next_log)?;
+    }
+
+    if current_log.exists() && sorted_gens(path)?.is_empty() {
+        fs::rename(&current_log, log_path(path, 1))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the generation numbers of every log file in `path`, oldest first.
+fn sorted_gens(path: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+
+        if entry_path.extension() != Some(OsStr::new("log")) {
+            continue;
+        }
+
+        if let Some(gen) = entry_path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse().ok())
+        {
+            gens.push(gen);
+        }
+    }
+
+    gens.sort_unstable();
+
+    Ok(gens)
+}
+
+/// Deletes the generations before `safe_point`, which compaction has
+/// replaced, and their hint files, except the ones a snapshot in `pins`
+/// still reads from.
+///
+/// The value log files in `collected` go along with the last generation
+/// from before the compaction that collected them, since nothing after it
+/// points into them.
+fn remove_stale_gens(
+    path: &Path,
+    safe_point: u64,
+    pins: &BTreeMap<u64, usize>,
+    collected: &mut BTreeMap<u64, Vec<u64>>,
+) -> Result<()> {
+    let oldest = pins
+        .keys()
+        .next()
+        .map_or(safe_point, |&pinned| pinned.min(safe_point));
+
+    while let Some(entry) = collected.first_entry() {
+        if *entry.key() > oldest {
+            break;
+        }
+
+        for gen in entry.remove() {
+            match fs::remove_file(value_path(path, gen)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::from(e)),
+                _ => {}
+            }
+        }
+    }
+
+    for gen in sorted_gens(path)? {
+        if gen < oldest {
+            // Hints go first, so that they never outlive their generation.
+            match fs::remove_file(hint_path(path, gen)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::from(e)),
+                _ => {}
+            }
+
+            fs::remove_file(log_path(path, gen))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens a generation for appending and returns it with its length. The
+/// format header is written first if the file is new.
+fn new_log_file(path: &Path, gen: u64) -> Result<(File, u64)> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log_path(path, gen))?;
+    let mut len = file.metadata()?.len();
+
+    if len < record::FILE_HEADER.len() as u64 {
+        file.set_len(0)?;
+        file.write_all(record::FILE_HEADER)?;
+        len = record::FILE_HEADER.len() as u64;
+    }
+
+    Ok((file, len))
+}
+
+fn log_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.log", gen))
+}
+
+fn compacting_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.log.compacting", gen))
+}
+
+fn hint_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.hint", gen))
+}
+
+fn compacting_hint_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.hint.compacting", gen))
+}
+
+fn value_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.vlog", gen))
+}
+
+fn compacting_value_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.vlog.compacting", gen))
+}
+
+fn migrating_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.log.migrating", gen))
+}
@@ -0,0 +1,130 @@
+use std::collections::{btree_map, BTreeMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use super::{KvStore, KvStoreOptions};
+use crate::engines::check_namespace;
+use crate::{NamespaceInUse, Result};
+
+/// The namespaces of a store, each a [`KvStore`] of its own in a
+/// subdirectory, with its own index, generations and compaction.
+///
+/// Every handle shares one of these, so a namespace is only ever opened once
+/// however many handles ask for it.
+pub(super) struct Namespaces {
+    path: PathBuf,
+    options: KvStoreOptions,
+    // The stores kept here have no `Namespaces` of their own, which would
+    // make a cycle. `open` hands out clones that point back here.
+    open: Mutex<BTreeMap<String, KvStore>>,
+}
+
+impl Namespaces {
+    pub(super) fn new(path: PathBuf, options: KvStoreOptions) -> Namespaces {
+        Namespaces {
+            path,
+            options,
+            open: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns a handle to the namespace called `name`, opening or creating
+    /// it if no handle has asked for it yet.
+    pub(super) fn open(self: &Arc<Self>, name: &str) -> Result<KvStore> {
+        let mut open = self.open.lock().unwrap();
+
+        self.open_locked(&mut open, name)
+    }
+
+    /// Returns a handle to the namespace called `name` like `open`, or `None`
+    /// if it has no directory, in which case none is created.
+    pub(super) fn find(self: &Arc<Self>, name: &str) -> Result<Option<KvStore>> {
+        let mut open = self.open.lock().unwrap();
+
+        if !open.contains_key(name) && !self.path.join(name).is_dir() {
+            return Ok(None);
+        }
+
+        self.open_locked(&mut open, name).map(Some)
+    }
+
+    fn open_locked(
+        self: &Arc<Self>,
+        open: &mut BTreeMap<String, KvStore>,
+        name: &str,
+    ) -> Result<KvStore> {
+        let store = match open.entry(name.to_owned()) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => entry.insert(KvStore::open_log(
+                self.path.join(name),
+                self.options.clone(),
+                None,
+            )?),
+        };
+
+        Ok(KvStore {
+            namespaces: Some(Arc::clone(self)),
+            ..store.clone()
+        })
+    }
+
+    /// Closes the namespace called `name` and deletes its directory. Returns
+    /// whether there was one, or fails with `NamespaceInUse` while any handle
+    /// to it other than the one kept here is alive.
+    pub(super) fn remove(&self, name: &str) -> Result<bool> {
+        let mut open = self.open.lock().unwrap();
+
+        // Every handle shares the background compaction, and nothing else
+        // does.
+        if let Some(store) = open.get(name) {
+            if Arc::strong_count(&store.background) > 1 {
+                return Err(Error::from(NamespaceInUse(name.to_owned())));
+            }
+        }
+
+        // Dropping the last handle waits for a running compaction, so it
+        // cannot write into the directory once it is gone.
+        open.remove(name);
+
+        match fs::remove_dir_all(self.path.join(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    /// Returns the name of every namespace on disk, in order.
+    pub(super) fn names(&self) -> Result<Vec<String>> {
+        let _open = self.open.lock().unwrap();
+
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            let entry = entry?;
+
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(name) = entry.file_name().to_str() {
+                if check_namespace(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+}
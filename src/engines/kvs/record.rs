@@ -0,0 +1,439 @@
+use std::io::{self, Read};
+
+use failure::Error;
+use serde::Deserialize;
+
+use super::value::ValuePos;
+use crate::{CorruptRecord, Result};
+
+/// Written at the start of every generation in the binary format. Files
+/// without it hold the older newline-delimited JSON records.
+pub(super) const FILE_HEADER: &[u8; 8] = b"KVSLOG\x00\x02";
+
+/// Checksum (u32), flags (u8), key length (u32) and value length (u32), all
+/// little-endian. The checksum covers everything after it.
+pub(super) const HEADER_LEN: usize = 13;
+
+const TOMBSTONE: u8 = 1;
+
+/// Set when the header is followed by an expiry time: milliseconds since
+/// the Unix epoch as a little-endian `u64`.
+const EXPIRES: u8 = 2;
+
+const EXPIRY_LEN: usize = 8;
+
+/// Set on a record whose body is a sequence of whole records written as one
+/// atomic batch.
+const BATCH: u8 = 4;
+
+/// Set when the header, and expiry time if any, is followed by the record's
+/// sequence number as a little-endian `u64`. Records written before sequence
+/// numbers existed lack it and count as sequence number 0.
+const SEQUENCE: u8 = 8;
+
+const SEQUENCE_LEN: usize = 8;
+
+/// Set when the value is kept in the value log, and the record holds a
+/// [`ValuePos`] pointing at it in its place.
+const SEPARATE: u8 = 16;
+
+/// Set on a record with no key or value, only a sequence number at least as
+/// high as that of every record written before it, including the ones
+/// compaction dropped. Compaction starts every generation it writes with
+/// one, so sequence numbers are never handed out twice.
+const SEQUENCE_MARK: u8 = 32;
+
+/// One decoded log record. A `None` value marks a removed key, or a sequence
+/// mark.
+pub(super) struct Record {
+    pub(super) key: Vec<u8>,
+    pub(super) value: Option<Value>,
+    pub(super) expires_at: Option<u64>,
+    pub(super) seq: u64,
+    pub(super) mark: bool,
+}
+
+/// The value of a record, either held in the record itself or kept in the
+/// value log.
+pub(super) enum Value {
+    Inline(Vec<u8>),
+    Separate(ValuePos),
+}
+
+pub(super) fn encode(
+    key: &[u8],
+    value: Option<&[u8]>,
+    expires_at: Option<u64>,
+    seq: u64,
+) -> Vec<u8> {
+    match value {
+        Some(value) => encode_with(SEQUENCE, key, value, expires_at, seq),
+        None => encode_with(SEQUENCE | TOMBSTONE, key, &[], expires_at, seq),
+    }
+}
+
+/// Encodes a record whose value is kept in the value log at `value_pos`.
+pub(super) fn encode_separate(
+    key: &[u8],
+    value_pos: ValuePos,
+    expires_at: Option<u64>,
+    seq: u64,
+) -> Vec<u8> {
+    encode_with(
+        SEQUENCE | SEPARATE,
+        key,
+        &value_pos.encode(),
+        expires_at,
+        seq,
+    )
+}
+
+/// Encodes a sequence mark for `seq`.
+pub(super) fn encode_sequence_mark(seq: u64) -> Vec<u8> {
+    encode_with(SEQUENCE | SEQUENCE_MARK, &[], &[], None, seq)
+}
+
+fn encode_with(
+    mut flags: u8,
+    key: &[u8],
+    value: &[u8],
+    expires_at: Option<u64>,
+    seq: u64,
+) -> Vec<u8> {
+    let mut record =
+        Vec::with_capacity(HEADER_LEN + EXPIRY_LEN + SEQUENCE_LEN + key.len() + value.len());
+
+    if expires_at.is_some() {
+        flags |= EXPIRES;
+    }
+
+    record.extend_from_slice(&[0; 4]);
+    record.push(flags);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+
+    if let Some(expires_at) = expires_at {
+        record.extend_from_slice(&expires_at.to_le_bytes());
+    }
+
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+
+    let checksum = crc32fast::hash(&record[4..]);
+
+    record[..4].copy_from_slice(&checksum.to_le_bytes());
+
+    record
+}
+
+/// Wraps already encoded records into a single batch record, so that
+/// replaying the log applies either all of them or none.
+///
+/// The batch has the usual header, with a value length covering the records
+/// inside it, and a checksum over all of them.
+pub(super) fn encode_batch(records: &[Vec<u8>]) -> Vec<u8> {
+    let body_len: usize = records.iter().map(Vec::len).sum();
+    let mut batch = Vec::with_capacity(HEADER_LEN + body_len);
+
+    batch.extend_from_slice(&[0; 4]);
+    batch.push(BATCH);
+    batch.extend_from_slice(&0u32.to_le_bytes());
+    batch.extend_from_slice(&(body_len as u32).to_le_bytes());
+
+    for record in records {
+        batch.extend_from_slice(record);
+    }
+
+    let checksum = crc32fast::hash(&batch[4..]);
+
+    batch[..4].copy_from_slice(&checksum.to_le_bytes());
+
+    batch
+}
+
+/// Decodes a whole record read from `offset` in generation `gen`.
+pub(super) fn decode(record: &[u8], gen: u64, offset: u64) -> Result<Record> {
+    verify(record, gen, offset)?;
+
+    if record[4] & BATCH != 0 {
+        return Err(Error::from(CorruptRecord { gen, offset }));
+    }
+
+    parse(record).ok_or_else(|| Error::from(CorruptRecord { gen, offset }))
+}
+
+/// A record found while replaying the log, with its offset relative to the
+/// start of the record read, which differs for records inside a batch.
+pub(super) struct Entry {
+    pub(super) offset: u64,
+    pub(super) len: u64,
+    pub(super) record: Record,
+}
+
+/// Reads the next record from a generation being replayed, given the
+/// number of bytes left in the file, and returns what it holds along with
+/// its length. That is a single entry unless it is a batch.
+///
+/// Returns `None` when the record runs past the end of the file, or fails
+/// its checksum and no intact record starts where its length says it ends:
+/// that is a torn write. A bad record that one does follow is reported as
+/// corruption.
+pub(super) fn read_next<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    offset: u64,
+    remaining: u64,
+) -> Result<Option<(Vec<Entry>, u64)>> {
+    if remaining < HEADER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut record = vec![0; HEADER_LEN];
+
+    reader.read_exact(&mut record)?;
+
+    let len = record_len(&record) as u64;
+
+    if len > remaining {
+        return Ok(None);
+    }
+
+    record.resize(len as usize, 0);
+    reader.read_exact(&mut record[HEADER_LEN..])?;
+
+    if verify(&record, gen, offset).is_err() {
+        return damaged(reader, gen, offset, remaining - len);
+    }
+
+    if record[4] & BATCH == 0 {
+        let record = parse(&record).ok_or(CorruptRecord { gen, offset })?;
+
+        return Ok(Some((
+            vec![Entry {
+                offset: 0,
+                len,
+                record,
+            }],
+            len,
+        )));
+    }
+
+    let mut entries = Vec::new();
+    let mut position = HEADER_LEN;
+
+    while position < record.len() {
+        let inner = &record[position..];
+        let inner_offset = offset + position as u64;
+
+        if inner.len() < HEADER_LEN || inner.len() < record_len(inner) {
+            return Err(Error::from(CorruptRecord {
+                gen,
+                offset: inner_offset,
+            }));
+        }
+
+        let inner_len = record_len(inner);
+
+        entries.push(Entry {
+            offset: position as u64,
+            len: inner_len as u64,
+            record: decode(&inner[..inner_len], gen, inner_offset)?,
+        });
+
+        position += inner_len;
+    }
+
+    Ok(Some((entries, len)))
+}
+
+/// Decides what a record that fails its checksum is, given the reader just
+/// past where its length says it ends and the bytes left from there. Writes
+/// only ever append, so it was damaged in place if an intact record starts
+/// right there, and is a torn write otherwise.
+fn damaged<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    offset: u64,
+    remaining: u64,
+) -> Result<Option<(Vec<Entry>, u64)>> {
+    if remaining < HEADER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut next = vec![0; HEADER_LEN];
+
+    reader.read_exact(&mut next)?;
+
+    let len = record_len(&next) as u64;
+
+    if len > remaining {
+        return Ok(None);
+    }
+
+    next.resize(len as usize, 0);
+    reader.read_exact(&mut next[HEADER_LEN..])?;
+
+    match verify(&next, gen, offset) {
+        Ok(()) => Err(Error::from(CorruptRecord { gen, offset })),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Checks a record's length and checksum.
+fn verify(record: &[u8], gen: u64, offset: u64) -> Result<()> {
+    if record.len() < HEADER_LEN
+        || record.len() != record_len(record)
+        || u32_at(record, 0) != crc32fast::hash(&record[4..])
+    {
+        return Err(Error::from(CorruptRecord { gen, offset }));
+    }
+
+    Ok(())
+}
+
+/// Splits a verified record into its parts, or returns `None` if it points
+/// into the value log with something that is not a [`ValuePos`].
+fn parse(record: &[u8]) -> Option<Record> {
+    let flags = record[4];
+    let (key_len, _) = body_lens(record);
+    let mut body = &record[HEADER_LEN..];
+    let expires_at = match flags & EXPIRES {
+        0 => None,
+        _ => Some(take_u64(&mut body)),
+    };
+    let seq = match flags & SEQUENCE {
+        0 => 0,
+        _ => take_u64(&mut body),
+    };
+
+    let key = body[..key_len].to_vec();
+    let mark = flags & SEQUENCE_MARK != 0;
+    let value = match (flags & TOMBSTONE, flags & SEPARATE) {
+        _ if mark => None,
+        (0, 0) => Some(Value::Inline(body[key_len..].to_vec())),
+        (0, _) => Some(Value::Separate(ValuePos::decode(&body[key_len..])?)),
+        _ => None,
+    };
+
+    Some(Record {
+        key,
+        value,
+        expires_at,
+        seq,
+        mark,
+    })
+}
+
+/// Reads a little-endian `u64` off the front of `bytes`.
+fn take_u64(bytes: &mut &[u8]) -> u64 {
+    let (number, rest) = bytes.split_at(8);
+    let mut array = [0; 8];
+
+    array.copy_from_slice(number);
+    *bytes = rest;
+
+    u64::from_le_bytes(array)
+}
+
+/// A record as it was written before the binary format.
+#[derive(Deserialize)]
+enum JsonRecord {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonRecord> for Record {
+    fn from(record: JsonRecord) -> Record {
+        match record {
+            JsonRecord::Set { key, value } => Record {
+                key: key.into_bytes(),
+                value: Some(Value::Inline(value.into_bytes())),
+                expires_at: None,
+                seq: 0,
+                mark: false,
+            },
+            JsonRecord::Remove { key } => Record {
+                key: key.into_bytes(),
+                value: None,
+                expires_at: None,
+                seq: 0,
+                mark: false,
+            },
+        }
+    }
+}
+
+/// Decodes one newline-terminated JSON record from a log written before the
+/// binary format, with or without the CRC32 prefix.
+pub(super) fn decode_json(line: &[u8], gen: u64, offset: u64) -> Result<Record> {
+    let corrupt = || Error::from(CorruptRecord { gen, offset });
+
+    let line = match line.split_last() {
+        Some((b'\n', line)) => line,
+        _ => return Err(corrupt()),
+    };
+
+    let json = match line.first() {
+        Some(b'{') => line,
+        _ => checked_json(line).ok_or_else(corrupt)?,
+    };
+
+    serde_json::from_slice::<JsonRecord>(json)
+        .map(Record::from)
+        .map_err(|_| corrupt())
+}
+
+/// Strips the CRC32 prefix from a JSON record, if it matches.
+fn checked_json(line: &[u8]) -> Option<&[u8]> {
+    if line.len() < 9 || line[8] != b' ' {
+        return None;
+    }
+
+    let (checksum, json) = (&line[..8], &line[9..]);
+    let checksum = std::str::from_utf8(checksum)
+        .ok()
+        .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())?;
+
+    if checksum != crc32fast::hash(json) {
+        return None;
+    }
+
+    Some(json)
+}
+
+/// Checks whether a generation is in the binary format. A file holding only
+/// part of the header counts, since that is what a crash right after
+/// creating it leaves behind.
+pub(super) fn is_binary<R: Read>(reader: &mut R) -> io::Result<bool> {
+    let mut header = Vec::with_capacity(FILE_HEADER.len());
+
+    reader
+        .take(FILE_HEADER.len() as u64)
+        .read_to_end(&mut header)?;
+
+    Ok(FILE_HEADER.starts_with(&header))
+}
+
+/// The length of a whole record, worked out from its header.
+fn record_len(header: &[u8]) -> usize {
+    let (key_len, value_len) = body_lens(header);
+    let expiry_len = match header[4] & EXPIRES {
+        0 => 0,
+        _ => EXPIRY_LEN,
+    };
+    let seq_len = match header[4] & SEQUENCE {
+        0 => 0,
+        _ => SEQUENCE_LEN,
+    };
+
+    HEADER_LEN + expiry_len + seq_len + key_len + value_len
+}
+
+fn body_lens(record: &[u8]) -> (usize, usize) {
+    (u32_at(record, 5) as usize, u32_at(record, 9) as usize)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
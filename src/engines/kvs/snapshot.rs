@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::error;
+
+use super::value::ValueReaders;
+use super::{read_value, remove_stale_gens, CommandPos};
+use crate::engines::{scan_bounds, KvsSnapshot};
+use crate::Result;
+
+/// How many snapshots read from each generation onwards, keyed by the oldest
+/// generation they need.
+pub(super) type Pins = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// The value log files each compaction collected, keyed by the generation it
+/// wrote, until no snapshot can read from them any more.
+///
+/// Lock it after `Pins` when holding both.
+pub(super) type Collected = Arc<Mutex<BTreeMap<u64, Vec<u64>>>>;
+
+/// A read-only view of a [`KvStore`](super::KvStore) as of the moment
+/// [`snapshot`](crate::KvsEngine::snapshot) was called.
+///
+/// The snapshot holds its own copy of the index, so it costs memory in
+/// proportion to the number of keys but never blocks writers. Generations
+/// it reads from are kept on disk until it is dropped, even if compaction
+/// has replaced them, and so are the value log files it reads from.
+pub struct KvStoreSnapshot {
+    path: Arc<PathBuf>,
+    index: BTreeMap<Vec<u8>, CommandPos>,
+    taken_at: u64,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+    value_readers: RefCell<ValueReaders>,
+    _pin: Pin,
+}
+
+/// Keeps the generations from the safe point at the time it was taken on,
+/// and the value log files they point into, on disk until it is dropped,
+/// even if compaction replaces them in the meantime.
+pub(super) struct Pin {
+    path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU64>,
+    pins: Pins,
+    collected: Collected,
+    gen: u64,
+}
+
+impl Pin {
+    /// Pins the generations the index refers to. The caller holds the index
+    /// lock, so `safe_point` is the oldest of them.
+    pub(super) fn new(
+        path: Arc<PathBuf>,
+        safe_point: Arc<AtomicU64>,
+        pins: Pins,
+        collected: Collected,
+    ) -> Pin {
+        let gen = safe_point.load(Ordering::SeqCst);
+
+        *pins.lock().unwrap().entry(gen).or_insert(0) += 1;
+
+        Pin {
+            path,
+            safe_point,
+            pins,
+            collected,
+            gen,
+        }
+    }
+}
+
+impl Drop for Pin {
+    /// Unpins the generations and deletes the ones compaction left behind
+    /// that nothing else needs, along with the value log files it collected.
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+
+        if let Some(count) = pins.get_mut(&self.gen) {
+            *count -= 1;
+
+            if *count == 0 {
+                pins.remove(&self.gen);
+            }
+        }
+
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+
+        // Nothing was kept back for this pin unless compaction ran since.
+        if self.gen == safe_point {
+            return;
+        }
+
+        let mut collected = self.collected.lock().unwrap();
+
+        if let Err(e) = remove_stale_gens(&self.path, safe_point, &pins, &mut collected) {
+            error!("unable to remove compacted generations: {}", e);
+        }
+    }
+}
+
+impl KvStoreSnapshot {
+    /// Takes a snapshot of `index`, with a pin taken under the same lock.
+    pub(super) fn new(
+        path: Arc<PathBuf>,
+        index: &BTreeMap<Vec<u8>, CommandPos>,
+        taken_at: u64,
+        pin: Pin,
+    ) -> KvStoreSnapshot {
+        KvStoreSnapshot {
+            path,
+            index: index.clone(),
+            taken_at,
+            readers: RefCell::new(BTreeMap::new()),
+            value_readers: RefCell::new(ValueReaders::new()),
+            _pin: pin,
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(self.taken_at) => Ok(Some(read_value(
+                &mut self.readers.borrow_mut(),
+                &mut self.value_readers.borrow_mut(),
+                &self.path,
+                cmd_pos,
+            )?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut readers = self.readers.borrow_mut();
+        let mut value_readers = self.value_readers.borrow_mut();
+
+        self.index
+            .range(bounds)
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(self.taken_at))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, &cmd_pos)| {
+                let value = read_value(&mut readers, &mut value_readers, &self.path, cmd_pos)?;
+
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    /// Closes the files this snapshot read from before its pin lets
+    /// compaction delete them.
+    fn drop(&mut self) {
+        self.readers.borrow_mut().clear();
+        self.value_readers.replace(ValueReaders::new());
+    }
+}
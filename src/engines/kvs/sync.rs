@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use log::error;
+
+use crate::engines::Durability;
+use crate::Result;
+
+/// Syncs the log to disk as the store's [`Durability`] asks.
+///
+/// Writers append under the writer lock and then wait here without it, so
+/// writers that arrive while a sync is running all wait for the next one,
+/// which covers every one of their records: a group commit.
+///
+/// The value log file being appended to, if any, is synced first, so a
+/// record never reaches the disk ahead of the value it points to.
+pub(super) struct Syncer {
+    durability: Durability,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+struct SyncState {
+    // A handle to the generation being appended to, and to its value log
+    // file once it has one.
+    file: Arc<File>,
+    values: Option<Arc<File>>,
+    // The sequence number of the last record appended, and of the last one
+    // known to be on disk.
+    written_seq: u64,
+    synced_seq: u64,
+    // Whether some thread is syncing right now.
+    syncing: bool,
+}
+
+impl Syncer {
+    /// `file` is the generation being appended to, whose records up to
+    /// `seq` are taken to be on disk already.
+    pub(super) fn new(durability: Durability, file: File, seq: u64) -> Syncer {
+        Syncer {
+            durability,
+            state: Mutex::new(SyncState {
+                file: Arc::new(file),
+                values: None,
+                written_seq: seq,
+                synced_seq: seq,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    pub(super) fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Records that everything up to `seq` has been appended. The caller
+    /// holds the writer lock.
+    pub(super) fn written(&self, seq: u64) {
+        if self.durability != Durability::Never {
+            self.state.lock().unwrap().written_seq = seq;
+        }
+    }
+
+    /// Makes `file` the value log file to sync along with the generation
+    /// being appended to.
+    pub(super) fn values(&self, file: File) {
+        self.state.lock().unwrap().values = Some(Arc::new(file));
+    }
+
+    /// Syncs the generation the writer is leaving, and its value log file,
+    /// and makes `file` the one to sync from now on. The caller holds the
+    /// writer lock, so `seq` is the last record in the old generation.
+    pub(super) fn roll(
+        &self,
+        old: &File,
+        old_values: Option<&File>,
+        file: File,
+        seq: u64,
+    ) -> Result<()> {
+        if self.durability != Durability::Never {
+            if let Some(old_values) = old_values {
+                old_values.sync_data()?;
+            }
+
+            old.sync_data()?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.file = Arc::new(file);
+        state.values = None;
+        state.written_seq = seq;
+        state.synced_seq = state.synced_seq.max(seq);
+
+        Ok(())
+    }
+
+    /// Returns once the record numbered `seq` is as durable as the store
+    /// promises, syncing it first with [`Durability::EveryWrite`].
+    pub(super) fn commit(&self, seq: u64) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => self.sync_to(seq),
+            _ => Ok(()),
+        }
+    }
+
+    /// Syncs everything appended so far.
+    pub(super) fn sync(&self) -> Result<()> {
+        let seq = self.state.lock().unwrap().written_seq;
+
+        self.sync_to(seq)
+    }
+
+    /// Waits until every record up to `seq` is on disk. If no other thread
+    /// is syncing, this one does, on behalf of everything appended so far.
+    fn sync_to(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.synced_seq >= seq {
+                return Ok(());
+            }
+
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+
+                continue;
+            }
+
+            let target = state.written_seq;
+            let file = Arc::clone(&state.file);
+            let values = state.values.clone();
+
+            state.syncing = true;
+            drop(state);
+
+            let result = match values {
+                Some(values) => values.sync_data().and_then(|()| file.sync_data()),
+                None => file.sync_data(),
+            };
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+
+            if result.is_ok() {
+                state.synced_seq = state.synced_seq.max(target);
+            }
+
+            self.synced.notify_all();
+
+            result?;
+        }
+    }
+}
+
+/// The thread that syncs the log every so often for
+/// [`Durability::Interval`].
+///
+/// Dropping it syncs one last time and stops the thread.
+pub(super) struct BackgroundSync {
+    sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundSync {
+    /// Returns `None` unless the syncer's durability is an interval.
+    pub(super) fn spawn(syncer: Arc<Syncer>) -> Result<Option<BackgroundSync>> {
+        let interval = match syncer.durability() {
+            Durability::Interval(interval) => interval,
+            _ => return Ok(None),
+        };
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+
+        let thread = thread::Builder::new()
+            .name("kvs-sync".to_owned())
+            .spawn(move || loop {
+                let stop = receiver.recv_timeout(interval) != Err(RecvTimeoutError::Timeout);
+
+                if let Err(e) = syncer.sync() {
+                    error!("unable to sync the log: {}", e);
+                }
+
+                if stop {
+                    break;
+                }
+            })?;
+
+        Ok(Some(BackgroundSync {
+            sender,
+            thread: Some(thread),
+        }))
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        let _ = self.sender.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
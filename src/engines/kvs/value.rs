@@ -0,0 +1,206 @@
+use std::collections::{btree_map, BTreeMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use failure::Error;
+
+use super::value_path;
+use crate::{CorruptValue, Result};
+
+/// Written at the start of every value log file.
+pub(super) const VALUE_HEADER: &[u8; 8] = b"KVSVAL\x00\x01";
+
+/// Checksum (u32), key length (u32) and value length (u32), all
+/// little-endian. The checksum covers everything after it, and the key and
+/// value follow.
+const ENTRY_HEADER_LEN: usize = 12;
+
+/// Where a value kept in the value log is: the value log file, numbered
+/// after the generation that was being appended to when it was written,
+/// and the offset and length of its entry there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct ValuePos {
+    pub(super) gen: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+}
+
+/// The length of an encoded [`ValuePos`].
+const VALUE_POS_LEN: usize = 24;
+
+impl ValuePos {
+    /// The generation, offset and length, each a little-endian `u64`.
+    pub(super) fn encode(&self) -> [u8; VALUE_POS_LEN] {
+        let mut bytes = [0; VALUE_POS_LEN];
+
+        bytes[..8].copy_from_slice(&self.gen.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.pos.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.len.to_le_bytes());
+
+        bytes
+    }
+
+    pub(super) fn decode(bytes: &[u8]) -> Option<ValuePos> {
+        let (gen, rest) = bytes.split_first_chunk::<8>()?;
+        let (pos, rest) = rest.split_first_chunk::<8>()?;
+        let (len, rest) = rest.split_first_chunk::<8>()?;
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some(ValuePos {
+            gen: u64::from_le_bytes(*gen),
+            pos: u64::from_le_bytes(*pos),
+            len: u64::from_le_bytes(*len),
+        })
+    }
+}
+
+/// Encodes an entry of the value log. The key is kept alongside the value
+/// so that a value log file can be read on its own.
+pub(super) fn encode(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + key.len() + value.len());
+
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    entry.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(value);
+
+    let checksum = crc32fast::hash(&entry[4..]);
+
+    entry[..4].copy_from_slice(&checksum.to_le_bytes());
+
+    entry
+}
+
+/// Checks an entry's lengths and checksum.
+fn verify(entry: &[u8], value_pos: ValuePos) -> Result<()> {
+    let corrupt = || {
+        Error::from(CorruptValue {
+            gen: value_pos.gen,
+            offset: value_pos.pos,
+        })
+    };
+
+    if entry.len() < ENTRY_HEADER_LEN {
+        return Err(corrupt());
+    }
+
+    let checksum = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let key_len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+    let value_len = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+
+    if entry.len() != ENTRY_HEADER_LEN + key_len + value_len
+        || checksum != crc32fast::hash(&entry[4..])
+    {
+        return Err(corrupt());
+    }
+
+    Ok(())
+}
+
+/// The value of a verified entry.
+fn value_of(entry: &[u8]) -> &[u8] {
+    let key_len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+
+    &entry[ENTRY_HEADER_LEN + key_len..]
+}
+
+/// Open value log files, by generation.
+pub(super) struct ValueReaders {
+    // The safe point when the files were opened. Compaction moves it every
+    // time it runs, and may have collected any of them when it does.
+    safe_point: u64,
+    files: BTreeMap<u64, BufReader<File>>,
+}
+
+impl ValueReaders {
+    pub(super) fn new() -> ValueReaders {
+        ValueReaders {
+            safe_point: 0,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Closes every file if compaction has run since they were opened.
+    pub(super) fn close_stale(&mut self, safe_point: u64) {
+        if self.safe_point != safe_point {
+            self.files.clear();
+            self.safe_point = safe_point;
+        }
+    }
+
+    /// Reads the value at `value_pos`.
+    pub(super) fn read(&mut self, path: &Path, value_pos: ValuePos) -> Result<Vec<u8>> {
+        Ok(value_of(&self.read_entry(path, value_pos)?).to_vec())
+    }
+
+    /// Reads the whole entry at `value_pos`, checking it on the way.
+    pub(super) fn read_entry(&mut self, path: &Path, value_pos: ValuePos) -> Result<Vec<u8>> {
+        let reader = match self.files.entry(value_pos.gen) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let file = File::open(value_path(path, value_pos.gen))?;
+
+                entry.insert(BufReader::new(file))
+            }
+        };
+
+        let mut entry = vec![0; value_pos.len as usize];
+
+        reader.seek(SeekFrom::Start(value_pos.pos))?;
+        reader.read_exact(&mut entry)?;
+        verify(&entry, value_pos)?;
+
+        Ok(entry)
+    }
+}
+
+/// Opens a value log file for appending and returns it with its length. The
+/// header is written first if the file is new.
+pub(super) fn new_value_file(path: &Path, gen: u64) -> Result<(File, u64)> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(value_path(path, gen))?;
+    let mut len = file.metadata()?.len();
+
+    if len < VALUE_HEADER.len() as u64 {
+        file.set_len(0)?;
+        file.write_all(VALUE_HEADER)?;
+        len = VALUE_HEADER.len() as u64;
+    }
+
+    Ok((file, len))
+}
+
+/// Returns the size of every value log file in `path` by generation, not
+/// counting its header.
+pub(super) fn sizes(path: &Path) -> Result<BTreeMap<u64, u64>> {
+    let mut sizes = BTreeMap::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+
+        if entry_path.extension() != Some(OsStr::new("vlog")) {
+            continue;
+        }
+
+        if let Some(gen) = entry_path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse().ok())
+        {
+            let len = entry.metadata()?.len();
+
+            sizes.insert(gen, len.saturating_sub(VALUE_HEADER.len() as u64));
+        }
+    }
+
+    Ok(sizes)
+}
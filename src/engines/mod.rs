@@ -1,13 +1,296 @@
-use crate::Result;
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub trait KvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn remove(&mut self, key: String) -> Result<()>;
+use serde::{Deserialize, Serialize};
+
+use failure::Error;
+
+use crate::{CounterError, InvalidNamespace, Result};
+
+/// A key-value storage engine.
+///
+/// Engines are handles: cloning one is cheap and every clone operates on the
+/// same underlying store, so a clone can be moved to each thread that needs it.
+///
+/// Keys and values are arbitrary bytes.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// A point-in-time view returned by [`snapshot`](Self::snapshot).
+    type Snapshot: KvsSnapshot;
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Sets a value that expires once `ttl` has passed. From then on the key
+    /// reads as missing, as if it had been removed.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Returns how long `key` has left before it expires, or `None` if it
+    /// never does. Fails with `KeyNotFound` if there is no such key.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Applies every operation in `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the pairs with keys from `start` up to but not including
+    /// `end`, in key order. With no `end` the scan runs to the last key, and
+    /// at most `limit` pairs are returned.
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Replaces the value of `key` with `new` if it currently is `expected`.
+    /// A `None` expected value means the key must not exist, and a `None` new
+    /// value removes it.
+    ///
+    /// A key written this way no longer expires.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome>;
+
+    /// Returns the value `key` had as of sequence number `seq`: that of the
+    /// latest write to it numbered `seq` or lower. Every write is numbered,
+    /// in increasing order.
+    ///
+    /// Returns `None` if the key did not exist then, or if the version has
+    /// since been reclaimed. Fails with `Unsupported` on engines that keep
+    /// no history.
+    fn get_at(&self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the versions of `key` still kept, newest first and at most
+    /// `limit` of them. Fails with `Unsupported` on engines that keep no
+    /// history.
+    fn history(&self, key: Vec<u8>, limit: Option<usize>) -> Result<Vec<Version>>;
+
+    /// Returns a read-only view of the store as it is now. Writes made
+    /// afterwards, including through other clones, are not visible in it.
+    ///
+    /// Taking one copies the index or the data, so it costs time and memory
+    /// in proportion to the number of keys. Each engine's snapshot type says
+    /// which.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Returns a handle to the namespace called `name`, creating it if it does
+    /// not exist yet. Each namespace is a keyspace of its own, and the handle
+    /// works like this one but only sees the keys in it.
+    ///
+    /// Names are made of ASCII letters, digits, `-` and `_`, and may not
+    /// start with `_`. Anything else fails with `InvalidNamespace`.
+    fn namespace(&self, name: &str) -> Result<Self>;
+
+    /// Returns a handle to the namespace called `name` like
+    /// [`namespace`](Self::namespace), or `None` if it does not exist. Unlike
+    /// that, it never creates one.
+    fn existing_namespace(&self, name: &str) -> Result<Option<Self>>;
+
+    /// Deletes the namespace called `name` and every key in it, and returns
+    /// whether it existed. Fails with `NamespaceInUse` while any handle to it
+    /// is alive.
+    fn drop_namespace(&self, name: &str) -> Result<bool>;
+
+    /// Returns the names of every namespace, in order.
+    fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// Applies `batch` atomically, but only if every key in `watched` still
+    /// holds the value given for it (`None` for a missing key). Returns
+    /// whether the batch was applied.
+    ///
+    /// This is what [`transaction`](Self::transaction) commits with.
+    fn commit_batch(
+        &self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool>;
+
+    /// Runs `f` as a transaction over any number of keys and returns what it
+    /// returned. See [`Transaction`] for an example.
+    ///
+    /// If a key `f` read is changed by someone else before the transaction
+    /// commits, nothing is written and `f` runs again. After repeated
+    /// conflicts this fails with `TransactionConflict`.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<'_>) -> Result<T>,
+    {
+        transaction::run(self, f)
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the result. A
+    /// missing key counts as 0, and the key keeps any expiry time it has.
+    ///
+    /// Values are stored as decimal strings. Fails with a `CounterError` if
+    /// the current value is not an `i64` or the result would overflow.
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    /// Adds one to the integer stored at `key`, like [`incr_by`](Self::incr_by).
+    fn incr(&self, key: Vec<u8>) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    /// Subtracts one from the integer stored at `key`, like
+    /// [`incr_by`](Self::incr_by).
+    fn decr(&self, key: Vec<u8>) -> Result<i64> {
+        self.incr_by(key, -1)
+    }
+
+    /// Returns every pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+
+        self.scan(prefix, end, None)
+    }
+}
+
+/// A read-only view of a store as of the moment it was taken, for readers
+/// such as exports that need a consistent picture while writes go on.
+pub trait KvsSnapshot: Send + 'static {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the pairs with keys from `start` up to but not including
+    /// `end`, like [`KvsEngine::scan`].
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns every pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = prefix_end(&prefix);
+
+        self.scan(prefix, end, None)
+    }
+}
+
+/// When an engine syncs writes to disk, trading write latency for how much a
+/// crash of the machine can lose. With kvs a crash of the process alone loses
+/// nothing in any mode. sled holds writes in memory until its background
+/// flush, which runs every half second under `Never`, so there a crash of the
+/// process loses what was written since the last one unless every write is
+/// synced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are left for the operating system to flush whenever it likes.
+    Never,
+    /// Every write is synced before it returns.
+    EveryWrite,
+    /// Writes are synced in the background this often, so a crash loses at
+    /// most about this much.
+    Interval(Duration),
+}
+
+/// One version of a key, as returned by [`KvsEngine::history`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// The sequence number of the write that made this version.
+    pub seq: u64,
+    /// The value written, or `None` if the key was removed or has expired.
+    pub value: Option<Vec<u8>>,
+}
+
+/// What [`KvsEngine::compare_and_swap`] did.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CasOutcome {
+    /// The key held the expected value and now holds the new one.
+    Swapped,
+    /// The key held something else, so nothing was written.
+    Conflict {
+        /// The value the key held instead, or `None` if it did not exist.
+        current: Option<Vec<u8>>,
+    },
+}
+
+impl CasOutcome {
+    pub fn is_swapped(&self) -> bool {
+        *self == CasOutcome::Swapped
+    }
+}
+
+/// Returns the smallest key that sorts after every key starting with
+/// `prefix`, or `None` when no such key exists (an empty or all-`0xff`
+/// prefix).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+
+            return Some(end);
+        }
+    }
+
+    None
 }
 
+/// Adds `delta` to a counter's current value, where `None` counts as 0.
+fn add_to_counter(current: Option<&[u8]>, delta: i64) -> std::result::Result<i64, CounterError> {
+    let current = match current {
+        Some(current) => std::str::from_utf8(current)
+            .ok()
+            .and_then(|current| current.parse::<i64>().ok())
+            .ok_or(CounterError::NotAnInteger)?,
+        None => 0,
+    };
+
+    current.checked_add(delta).ok_or(CounterError::Overflow)
+}
+
+/// Fails with `InvalidNamespace` unless `name` is a valid namespace name, as
+/// described on [`KvsEngine::namespace`]. Names starting with `_` are left
+/// to engines for their own use.
+fn check_namespace(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('_')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::from(InvalidNamespace(name.to_owned()))),
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit expiry times are stored in.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// The expiry time of a key set now to live for `ttl`.
+fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// Turns the arguments of [`KvsEngine::scan`] into range bounds, or `None`
+/// if the range is empty.
+#[allow(clippy::type_complexity)]
+fn scan_bounds(start: Vec<u8>, end: Option<Vec<u8>>) -> Option<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+    match end {
+        Some(end) if end <= start => None,
+        Some(end) => Some((Bound::Included(start), Bound::Excluded(end))),
+        None => Some((Bound::Included(start), Bound::Unbounded)),
+    }
+}
+
+mod batch;
+mod cache;
 mod kvs;
 mod sled;
+pub(crate) mod transaction;
+
+pub use self::batch::WriteBatch;
+pub use self::cache::{CacheStats, CachedEngine};
+pub use self::transaction::Transaction;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvStore;
+pub use self::kvs::{CompactionRun, CompactionStats, KvStore, KvStoreOptions, KvStoreSnapshot};
+pub use self::sled::{SledKvStore, SledSnapshot};
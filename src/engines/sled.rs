@@ -1,50 +1,561 @@
-use super::KvsEngine;
+use super::batch::BatchOp;
+use super::{
+    add_to_counter, check_namespace, expires_at, now_millis, scan_bounds, CasOutcome, Durability,
+    KvsEngine, KvsSnapshot, Version, WriteBatch,
+};
 
-use crate::{KeyNotFound, Result};
+use crate::{CounterError, KeyNotFound, NamespaceInUse, Result, Unsupported};
 use failure::Error;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionResult};
+use sled::Transactional;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::str;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
+/// Tree holding the expiry time of every key that has one, as milliseconds
+/// since the Unix epoch in big-endian. Each namespace has its own, named
+/// after it.
+const EXPIRY_TREE: &str = "__kvs_expiry";
+
+/// Namespaces are trees of the same database, named after the namespace.
+#[derive(Clone)]
 pub struct SledKvStore {
     db: sled::Db,
+    tree: sled::Tree,
+    expiry: sled::Tree,
+    durability: Durability,
+    writes: Arc<Writes>,
+    // The `writes` of every namespace opened so far, so that all the handles
+    // to one namespace share them.
+    namespace_writes: Arc<Mutex<BTreeMap<String, Arc<Writes>>>>,
+}
+
+/// What the writers to one keyspace share with the snapshots of it.
+///
+/// sled has no point-in-time reads, so a snapshot copies the tree. Writes go
+/// on while it does, but first keep what the keys they change held for it,
+/// and the snapshot puts that back over its copy.
+#[derive(Default)]
+struct Writes {
+    // Writers share this lock, and a snapshot only takes it exclusively to
+    // start copying, so that no write is half done when it does.
+    lock: RwLock<()>,
+    copies: Mutex<Copies>,
+}
+
+/// The snapshots being copied, by an id of their own.
+#[derive(Default)]
+struct Copies {
+    next_id: u64,
+    running: BTreeMap<u64, Copying>,
+}
+
+struct Copying {
+    taken_at: u64,
+    // What every key written since the copy started held then, with `None`
+    // for a missing or expired key.
+    before: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// A read-only copy of a [`SledKvStore`] as of the moment
+/// [`snapshot`](crate::KvsEngine::snapshot) was called.
+///
+/// Every live pair is copied into memory when the snapshot is taken, which
+/// takes time and memory in proportion to the size of the store. Writes go
+/// on meanwhile, though each one first keeps the values it replaces for the
+/// snapshot.
+pub struct SledSnapshot {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
 impl SledKvStore {
+    /// Opens the store, flushing every write to disk before it returns.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvStore> {
+        SledKvStore::open_with(path, Durability::EveryWrite)
+    }
+
+    /// Opens the store with the given durability. sled's own background
+    /// flushes run at the interval, if there is one, and at sled's default
+    /// interval otherwise.
+    pub fn open_with(path: impl Into<PathBuf>, durability: Durability) -> Result<SledKvStore> {
         let path = path.into();
+        let flush_every_ms = match durability {
+            // sled keeps unflushed writes in the process's memory, so they are
+            // never left for longer than its default interval.
+            Durability::Never | Durability::EveryWrite => Some(500),
+            Durability::Interval(interval) => Some(interval.as_millis().max(1) as u64),
+        };
 
-        let db = sled::open(path.join("current_sled_log"))?;
+        let db = sled::Config::new()
+            .path(path.join("current_sled_log"))
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        let tree = (*db).clone();
+        let expiry = db.open_tree(EXPIRY_TREE)?;
 
-        Ok(SledKvStore { db })
+        Ok(SledKvStore {
+            db,
+            tree,
+            expiry,
+            durability,
+            writes: Arc::new(Writes::default()),
+            namespace_writes: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    /// Writes a value and its expiry time, or clears the expiry time if
+    /// there is none, in one transaction.
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let _writing = self.start_write(&[&key])?;
+        let result: TransactionResult<(), sled::Error> =
+            (&self.tree, &self.expiry).transaction(|(db, expiry)| {
+                db.insert(key.as_slice(), value.as_slice())?;
+
+                match expires_at {
+                    Some(expires_at) => expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                    None => expiry.remove(key.as_slice())?,
+                };
+
+                Ok(())
+            });
+
+        result?;
+        self.flush()?;
+
+        Ok(())
+    }
+
+    /// Takes the write lock shared, then keeps what `keys` hold for every
+    /// snapshot being copied, before they are written.
+    fn start_write(&self, keys: &[&[u8]]) -> Result<RwLockReadGuard<'_, ()>> {
+        let writing = self.writes.lock.read().unwrap();
+        let mut copies = self.writes.copies.lock().unwrap();
+
+        for copying in copies.running.values_mut() {
+            for &key in keys {
+                if copying.before.contains_key(key) {
+                    continue;
+                }
+
+                let value = match self.tree.get(key)? {
+                    Some(value) if !self.is_expired(key, copying.taken_at)? => Some(value.to_vec()),
+                    _ => None,
+                };
+
+                copying.before.insert(key.to_vec(), value);
+            }
+        }
+
+        Ok(writing)
+    }
+
+    /// Flushes the write just made, if every write is to be durable.
+    fn flush(&self) -> Result<()> {
+        if self.durability == Durability::EveryWrite {
+            self.tree.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the expiry time of `key`, if it has one.
+    fn expiry_of(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.expiry.get(key)?.map(|expiry| decode_expiry(&expiry)))
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(self
+            .expiry_of(key)?
+            .is_some_and(|expires_at| expires_at <= now))
+    }
+
+    /// Collects the pairs an iterator over the main tree yields, leaving out
+    /// keys expired by `now`.
+    fn collect(
+        &self,
+        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+        limit: Option<usize>,
+        now: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+
+        for entry in iter {
+            if pairs.len() == limit.unwrap_or(usize::MAX) {
+                break;
+            }
+
+            let (key, value) = entry?;
+
+            if !self.is_expired(&key, now)? {
+                pairs.push((key.to_vec(), value.to_vec()));
+            }
+        }
+
+        Ok(pairs)
     }
 }
 
 impl KvsEngine for SledKvStore {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let value = self.db.get(&key)?;
+    type Snapshot = SledSnapshot;
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.tree.get(&key)?;
 
         match value {
-            Some(vec) => Ok(Some(str::from_utf8(&vec).unwrap().to_owned())),
-            None => Ok(None),
+            Some(value) if !self.is_expired(&key, now_millis())? => Ok(Some(value.to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let _writing = self.start_write(&[&key])?;
+        let now = now_millis();
+        let removed: TransactionResult<bool, sled::Error> =
+            (&self.tree, &self.expiry).transaction(|(db, expiry)| {
+                let value = db.remove(key.as_slice())?;
+                let expires_at = expiry
+                    .remove(key.as_slice())?
+                    .map(|expiry| decode_expiry(&expiry));
+
+                Ok(value.is_some() && expires_at.is_none_or(|expires_at| expires_at > now))
+            });
+
+        let removed = removed?;
+        self.flush()?;
+
+        match removed {
+            true => Ok(()),
+            false => Err(Error::from(KeyNotFound)),
         }
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.into_bytes(), value.into_bytes())?;
+    // sled overwrites values in place, so no history is kept.
+    fn get_at(&self, _key: Vec<u8>, _seq: u64) -> Result<Option<Vec<u8>>> {
+        Err(Error::from(Unsupported("get_at")))
+    }
+
+    fn history(&self, _key: Vec<u8>, _limit: Option<usize>) -> Result<Vec<Version>> {
+        Err(Error::from(Unsupported("history")))
+    }
+
+    fn namespace(&self, name: &str) -> Result<SledKvStore> {
+        check_namespace(name)?;
+
+        let writes = self
+            .namespace_writes
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+
+        Ok(SledKvStore {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+            expiry: self.db.open_tree(expiry_tree(name))?,
+            durability: self.durability,
+            writes,
+            namespace_writes: Arc::clone(&self.namespace_writes),
+        })
+    }
+
+    // `open_tree` creates trees that are missing, so check for it first.
+    fn existing_namespace(&self, name: &str) -> Result<Option<SledKvStore>> {
+        check_namespace(name)?;
+
+        if !self
+            .db
+            .tree_names()
+            .iter()
+            .any(|tree| tree == name.as_bytes())
+        {
+            return Ok(None);
+        }
+
+        self.namespace(name).map(Some)
+    }
 
-        self.db.flush()?;
+    fn drop_namespace(&self, name: &str) -> Result<bool> {
+        check_namespace(name)?;
+
+        // Every handle to the namespace shares its `Writes`.
+        let mut namespace_writes = self.namespace_writes.lock().unwrap();
+
+        if let Some(writes) = namespace_writes.get(name) {
+            if Arc::strong_count(writes) > 1 {
+                return Err(Error::from(NamespaceInUse(name.to_owned())));
+            }
+        }
+
+        let existed = self.db.drop_tree(name)?;
+
+        self.db.drop_tree(expiry_tree(name))?;
+        namespace_writes.remove(name);
+
+        Ok(existed)
+    }
+
+    // Trees of our own start with `_`, which namespace names cannot, and so
+    // does sled's default tree.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names: Vec<_> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .filter(|name| check_namespace(name).is_ok())
+            .collect();
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let taken_at = now_millis();
+        let id = {
+            let _writes = self.writes.lock.write().unwrap();
+            let mut copies = self.writes.copies.lock().unwrap();
+            let id = copies.next_id;
+
+            copies.next_id += 1;
+            copies.running.insert(
+                id,
+                Copying {
+                    taken_at,
+                    before: BTreeMap::new(),
+                },
+            );
+
+            id
+        };
+
+        let copied = self.collect(self.tree.iter(), None, taken_at);
+        let copying = self.writes.copies.lock().unwrap().running.remove(&id);
+        let mut entries: BTreeMap<_, _> = copied?.into_iter().collect();
+
+        for (key, value) in copying.map(|copying| copying.before).unwrap_or_default() {
+            match value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+
+        Ok(SledSnapshot { entries })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.commit_batch(Vec::new(), batch)?;
 
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let result = self.db.remove(&key)?;
+    fn commit_batch(
+        &self,
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let keys = batch.keys();
+        let _writing = self.start_write(&keys.iter().map(Vec::as_slice).collect::<Vec<_>>())?;
+        let mut values = sled::Batch::default();
+        let mut expiry = sled::Batch::default();
+
+        for op in batch.ops {
+            let key = match op {
+                BatchOp::Set { key, value } => {
+                    values.insert(key.as_slice(), value);
+                    key
+                }
+                BatchOp::Remove { key } => {
+                    values.remove(key.as_slice());
+                    key
+                }
+            };
+
+            // Keys set by a batch never expire.
+            expiry.remove(key);
+        }
+
+        // sled aborts and reruns the closure if another transaction touches
+        // the watched keys before this one commits.
+        let now = now_millis();
+        let result: TransactionResult<bool, sled::Error> =
+            (&self.tree, &self.expiry).transaction(|(db, expiry_tree)| {
+                for (key, expected) in &watched {
+                    let expired = expiry_tree
+                        .get(key.as_slice())?
+                        .is_some_and(|expiry| decode_expiry(&expiry) <= now);
+                    let current = match db.get(key.as_slice())? {
+                        Some(value) if !expired => Some(value),
+                        _ => None,
+                    };
+
+                    if current.as_deref() != expected.as_deref() {
+                        return Ok(false);
+                    }
+                }
+
+                db.apply_batch(&values)?;
+                expiry_tree.apply_batch(&expiry)?;
+
+                Ok(true)
+            });
+
+        let committed = result?;
+        self.flush()?;
+
+        Ok(committed)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let _writing = self.start_write(&[&key])?;
+        // `Tree::compare_and_swap` would see the stale value of an expired
+        // key and leave its expiry behind, so compare inside a transaction
+        // over both trees instead.
+        let now = now_millis();
+        let result: TransactionResult<CasOutcome, sled::Error> = (&self.tree, &self.expiry)
+            .transaction(|(db, expiry)| {
+                let expired = expiry
+                    .get(key.as_slice())?
+                    .is_some_and(|expiry| decode_expiry(&expiry) <= now);
+                let current = match db.get(key.as_slice())? {
+                    Some(value) if !expired => Some(value.to_vec()),
+                    _ => None,
+                };
+
+                if current != expected {
+                    return Ok(CasOutcome::Conflict { current });
+                }
+
+                match &new {
+                    Some(value) => db.insert(key.as_slice(), value.as_slice())?,
+                    None => db.remove(key.as_slice())?,
+                };
+                expiry.remove(key.as_slice())?;
+
+                Ok(CasOutcome::Swapped)
+            });
+
+        let outcome = result?;
+        self.flush()?;
+
+        Ok(outcome)
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let _writing = self.start_write(&[&key])?;
+        let now = now_millis();
+        let result: TransactionResult<i64, CounterError> =
+            (&self.tree, &self.expiry).transaction(|(db, expiry)| {
+                let expired = expiry
+                    .get(key.as_slice())?
+                    .is_some_and(|expiry| decode_expiry(&expiry) <= now);
+                let current = match db.get(key.as_slice())? {
+                    Some(value) if !expired => Some(value),
+                    _ => None,
+                };
+                let counter = add_to_counter(current.as_deref(), delta)
+                    .map_err(ConflictableTransactionError::Abort)?;
+
+                db.insert(key.as_slice(), counter.to_string().as_bytes())?;
+
+                if expired {
+                    expiry.remove(key.as_slice())?;
+                }
+
+                Ok(counter)
+            });
+
+        let counter = match result {
+            Ok(counter) => counter,
+            Err(TransactionError::Abort(e)) => return Err(Error::from(e)),
+            Err(TransactionError::Storage(e)) => return Err(Error::from(e)),
+        };
+        self.flush()?;
+
+        Ok(counter)
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
 
-        self.db.flush()?;
+        if !self.tree.contains_key(&key)? {
+            return Err(Error::from(KeyNotFound));
+        }
 
-        match result {
-            Some(_) => Ok(()),
-            None => Err(Error::from(KeyNotFound)),
+        match self.expiry_of(&key)? {
+            Some(expires_at) if expires_at <= now => Err(Error::from(KeyNotFound)),
+            Some(expires_at) => Ok(Some(Duration::from_millis(expires_at - now))),
+            None => Ok(None),
         }
     }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+
+        self.collect(self.tree.range(bounds), limit, now_millis())
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.collect(self.tree.scan_prefix(prefix), None, now_millis())
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(&key).cloned())
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bounds = match scan_bounds(start, end) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(self
+            .entries
+            .range(bounds)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+fn expiry_tree(namespace: &str) -> String {
+    format!("{}/{}", EXPIRY_TREE, namespace)
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut expiry = [0; 8];
+
+    expiry.copy_from_slice(bytes);
+
+    u64::from_be_bytes(expiry)
 }
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use failure::Error;
+
+use super::{KvsEngine, WriteBatch};
+use crate::{Result, TransactionConflict};
+
+/// How many times a transaction is run before giving up on a key that keeps
+/// changing under it.
+const MAX_ATTEMPTS: usize = 64;
+
+/// The keys a transaction read, with the values it saw.
+pub(crate) type Watched = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// Where a transaction reads from and commits to: an engine, or a client
+/// talking to a server.
+pub(crate) trait Source {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Applies `batch` if every watched key still holds the value it was
+    /// read with, and returns whether it did.
+    fn commit(&mut self, watched: Watched, batch: WriteBatch) -> Result<bool>;
+}
+
+impl<E: KvsEngine> Source for &E {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KvsEngine::get(*self, key)
+    }
+
+    fn commit(&mut self, watched: Watched, batch: WriteBatch) -> Result<bool> {
+        self.commit_batch(watched, batch)
+    }
+}
+
+/// A read-modify-write transaction over several keys, as passed to
+/// [`KvsEngine::transaction`].
+///
+/// Reads go to the store and are remembered, while writes are buffered until
+/// the transaction commits. The commit only goes through if none of the keys
+/// read have changed since; otherwise the transaction runs again.
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set(b"from".to_vec(), b"10".to_vec()).unwrap();
+///
+/// store
+///     .transaction(|tx| {
+///         let from = tx.get(b"from".to_vec())?.unwrap();
+///         tx.set(b"to".to_vec(), from);
+///         tx.remove(b"from".to_vec());
+///         Ok(())
+///     })
+///     .unwrap();
+///
+/// assert_eq!(store.get(b"to".to_vec()).unwrap(), Some(b"10".to_vec()));
+/// ```
+pub struct Transaction<'a> {
+    source: &'a mut dyn Source,
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction<'_> {
+    /// Reads `key`, seeing this transaction's own writes. Reading the same
+    /// key twice returns the same value.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key).or_else(|| self.reads.get(&key)) {
+            return Ok(value.clone());
+        }
+
+        let value = self.source.get(key.clone())?;
+
+        self.reads.insert(key, value.clone());
+
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes `key`. Removing a key that does not exist is not an error.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+}
+
+/// Runs `f` in a transaction against `source` until it commits without a
+/// conflict, and returns what `f` returned for the run that committed.
+///
+/// An error from `f` ends the transaction without writing anything.
+pub(crate) fn run<S, T, F>(mut source: S, mut f: F) -> Result<T>
+where
+    S: Source,
+    F: FnMut(&mut Transaction<'_>) -> Result<T>,
+{
+    for _ in 0..MAX_ATTEMPTS {
+        let mut tx = Transaction {
+            source: &mut source,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let result = f(&mut tx)?;
+        let Transaction { reads, writes, .. } = tx;
+        let mut batch = WriteBatch::new();
+
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+
+        if source.commit(reads.into_iter().collect(), batch)? {
+            return Ok(result);
+        }
+    }
+
+    Err(Error::from(TransactionConflict))
+}
@@ -1,5 +1,6 @@
 // #![deny(missing_docs)]
-#![feature(seek_convenience)]
+// `failure_derive` expands `Fail` impls inside an anonymous const.
+#![allow(non_local_definitions)]
 
 //! # PNA Rust Project - Key Value KvStore
 //!
@@ -8,53 +9,181 @@
 //! use kvs::{KvStore, KvsEngine};
 //! use tempfile::TempDir;
 //! let temp_dir = TempDir::new().unwrap();
-//! let mut store = KvStore::open(temp_dir.path()).unwrap();
+//! let store = KvStore::open(temp_dir.path()).unwrap();
 //!
-//! store.set("key1".to_owned(), "value1".to_owned());
-//! assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+//! store.set(b"key1".to_vec(), b"value1".to_vec());
+//! assert_eq!(store.get(b"key1".to_vec()).unwrap(), Some(b"value1".to_vec()));
 //!
-//! store.remove("key1".to_owned());
-//! assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+//! store.remove(b"key1".to_vec());
+//! assert_eq!(store.get(b"key1".to_vec()).unwrap(), None);
 //! ```
 //!
-// #![feature(seek_convenience)]
 #[macro_use]
 extern crate failure_derive;
 extern crate log;
 extern crate sled;
 
+mod async_client;
+mod client;
+pub mod codec;
 mod engines;
+pub mod thread_pool;
 
-pub use engines::{KvStore, KvsEngine, SledKvStore};
+pub use async_client::AsyncKvsClient;
+pub use client::KvsClient;
+pub use engines::{
+    prefix_end, CacheStats, CachedEngine, CasOutcome, CompactionRun, CompactionStats, Durability,
+    KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot, SledKvStore, SledSnapshot,
+    Transaction, Version, WriteBatch,
+};
 
 use failure::Error;
 use log::{Level, Metadata, Record};
 use serde::{Deserialize, Serialize};
 use std::str;
+use std::time::Duration;
 
 // Some error type
 pub type Result<T> = std::result::Result<T, Error>;
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Response {
-    pub value: Option<String>,
+    pub value: Option<Vec<u8>>,
+    /// The pairs returned by a scan.
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The remaining lifetime returned by a TTL query.
+    pub ttl: Option<Duration>,
+    /// The outcome of a compare-and-swap.
+    pub cas: Option<CasOutcome>,
+    /// The new value of a counter.
+    pub counter: Option<i64>,
+    /// Whether a transaction committed.
+    pub committed: Option<bool>,
+    /// The versions returned by a history query.
+    pub history: Vec<Version>,
     pub error: Option<String>,
 }
 
 impl Response {
-    pub fn new(result: Result<Option<String>>) -> Response {
+    pub fn new(result: Result<Option<Vec<u8>>>) -> Response {
         match result {
-            Ok(value) => Response { value, error: None },
+            Ok(value) => Response {
+                value,
+                ..Response::default()
+            },
             Err(error) => Response {
-                value: None,
                 error: Some(error.to_string()),
+                ..Response::default()
             },
         }
     }
 
+    pub fn with_entries(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Response {
+        Response {
+            entries,
+            ..Response::default()
+        }
+    }
+
+    pub fn with_ttl(ttl: Option<Duration>) -> Response {
+        Response {
+            ttl,
+            ..Response::default()
+        }
+    }
+
+    pub fn with_cas(cas: CasOutcome) -> Response {
+        Response {
+            cas: Some(cas),
+            ..Response::default()
+        }
+    }
+
+    pub fn with_counter(counter: i64) -> Response {
+        Response {
+            counter: Some(counter),
+            ..Response::default()
+        }
+    }
+
+    pub fn with_committed(committed: bool) -> Response {
+        Response {
+            committed: Some(committed),
+            ..Response::default()
+        }
+    }
+
+    pub fn with_history(history: Vec<Version>) -> Response {
+        Response {
+            history,
+            ..Response::default()
+        }
+    }
+
     pub fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    pub fn into_result(self) -> Result<Option<Vec<u8>>> {
+        match self.error {
+            Some(error) => Err(failure::err_msg(error)),
+            None => Ok(self.value),
+        }
+    }
+
+    pub fn into_entries(self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.error {
+            Some(error) => Err(failure::err_msg(error)),
+            None => Ok(self.entries),
+        }
+    }
+
+    pub fn into_ttl(self) -> Result<Option<Duration>> {
+        match self.error {
+            Some(error) => Err(failure::err_msg(error)),
+            None => Ok(self.ttl),
+        }
+    }
+
+    pub fn into_history(self) -> Result<Vec<Version>> {
         match self.error {
-            Some(_) => true,
-            None => false,
+            Some(error) => Err(failure::err_msg(error)),
+            None => Ok(self.history),
+        }
+    }
+
+    pub fn into_counter(self) -> Result<i64> {
+        match (self.error, self.counter) {
+            (Some(error), _) => Err(failure::err_msg(error)),
+            (None, Some(counter)) => Ok(counter),
+            (None, None) => Err(failure::err_msg("Response carries no counter value")),
+        }
+    }
+
+    pub fn into_committed(self) -> Result<bool> {
+        match (self.error, self.committed) {
+            (Some(error), _) => Err(failure::err_msg(error)),
+            (None, Some(committed)) => Ok(committed),
+            (None, None) => Err(failure::err_msg("Response carries no commit outcome")),
+        }
+    }
+
+    pub fn into_cas(self) -> Result<CasOutcome> {
+        match (self.error, self.cas) {
+            (Some(error), _) => Err(failure::err_msg(error)),
+            (None, Some(cas)) => Ok(cas),
+            (None, None) => Err(failure::err_msg(
+                "Response carries no compare-and-swap outcome",
+            )),
+        }
+    }
+}
+
+impl Command {
+    /// Wraps the command so that it runs in the namespace called `namespace`.
+    pub fn in_namespace(self, namespace: impl Into<String>) -> Command {
+        Command::Namespaced {
+            namespace: namespace.into(),
+            command: Box::new(self),
         }
     }
 }
@@ -79,9 +208,114 @@ impl log::Log for Logger {
 #[fail(display = "Key not found")]
 pub struct KeyNotFound;
 
+#[derive(Fail, Debug)]
+pub enum CounterError {
+    #[fail(display = "Value is not an integer")]
+    NotAnInteger,
+    #[fail(display = "Increment would overflow")]
+    Overflow,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Transaction kept conflicting with other writes")]
+pub struct TransactionConflict;
+
+#[derive(Fail, Debug)]
+#[fail(display = "{} is not supported by this engine", _0)]
+pub struct Unsupported(pub &'static str);
+
+#[derive(Fail, Debug)]
+#[fail(display = "A thread pool needs at least one thread")]
+pub struct NoThreads;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid namespace name {:?}", _0)]
+pub struct InvalidNamespace(pub String);
+
+#[derive(Fail, Debug)]
+#[fail(display = "Namespace {:?} is still in use", _0)]
+pub struct NamespaceInUse(pub String);
+
+#[derive(Fail, Debug)]
+#[fail(display = "Corrupt record in generation {} at offset {}", gen, offset)]
+pub struct CorruptRecord {
+    pub gen: u64,
+    pub offset: u64,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Corrupt value in value log {} at offset {}", gen, offset)]
+pub struct CorruptValue {
+    pub gen: u64,
+    pub offset: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    /// See [`KvsEngine::compare_and_swap`].
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Incr {
+        key: Vec<u8>,
+    },
+    Decr {
+        key: Vec<u8>,
+    },
+    /// See [`KvsEngine::incr_by`].
+    IncrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    /// Commits a transaction: the `EXEC` of a `WATCH`/`MULTI`/`EXEC`
+    /// sequence, sent as one request. See [`KvsEngine::commit_batch`].
+    Exec {
+        watched: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    },
+    /// See [`KvsEngine::get_at`].
+    GetAt {
+        key: Vec<u8>,
+        seq: u64,
+    },
+    /// See [`KvsEngine::history`].
+    History {
+        key: Vec<u8>,
+        limit: Option<usize>,
+    },
+    /// See [`KvsEngine::scan`].
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    /// Runs `command` in the namespace called `namespace`, creating it if
+    /// needed. See [`KvsEngine::namespace`].
+    Namespaced {
+        namespace: String,
+        command: Box<Command>,
+    },
 }
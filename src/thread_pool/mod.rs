@@ -0,0 +1,35 @@
+use failure::Error;
+
+use crate::{NoThreads, Result};
+
+/// A pool of worker threads that the server runs requests on.
+pub trait ThreadPool {
+    /// Creates a pool with `threads` workers. Fails with `NoThreads` if
+    /// `threads` is 0.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on one of the pool's workers.
+    ///
+    /// A panicking job must not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// Checks that a pool is asked for at least one thread.
+fn check_threads(threads: u32) -> Result<()> {
+    match threads {
+        0 => Err(Error::from(NoThreads)),
+        _ => Ok(()),
+    }
+}
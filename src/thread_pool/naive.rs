@@ -0,0 +1,22 @@
+use std::thread;
+
+use super::{check_threads, ThreadPool};
+use crate::Result;
+
+/// Starts a new thread for every job and otherwise ignores the thread count.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(threads: u32) -> Result<NaiveThreadPool> {
+        check_threads(threads)?;
+
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
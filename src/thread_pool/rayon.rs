@@ -0,0 +1,30 @@
+use log::error;
+
+use super::{check_threads, ThreadPool};
+use crate::Result;
+
+/// A work-stealing pool backed by `rayon`.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        check_threads(threads)?;
+
+        // rayon would pick a number of its own for 0.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| error!("thread pool job panicked"))
+            .build()?;
+
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
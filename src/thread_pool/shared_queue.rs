@@ -0,0 +1,66 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{Receiver, Sender};
+use log::error;
+
+use super::{check_threads, ThreadPool};
+use crate::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of workers pulling jobs from a single shared channel.
+pub struct SharedQueueThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        let mut workers = Vec::with_capacity(threads as usize);
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+
+            workers.push(thread::Builder::new().spawn(move || run(receiver))?);
+        }
+
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .as_ref()
+            .expect("thread pool is shutting down")
+            .send(Box::new(job))
+            .expect("thread pool has no workers left");
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel lets each worker finish its queue and exit.
+        self.sender.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run(receiver: Receiver<Job>) {
+    for job in receiver {
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("thread pool job panicked");
+        }
+    }
+}
@@ -1,4 +1,7 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
+use kvs::{Command as KvsCommand, KvsClient};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -164,6 +167,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to reap server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     println!("test VERSION: {:?}", env!("CARGO_PKG_VERSION"));
@@ -186,6 +190,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -205,6 +210,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -214,6 +220,104 @@ fn cli_wrong_engine() {
     }
 }
 
+// The server should serve with any sync mode and refuse unknown ones
+#[test]
+fn cli_sync_modes() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "sometimes", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unknown sync mode"));
+
+    for (engine, sync, addr) in [
+        ("kvs", "every-write", "127.0.0.1:4008"),
+        ("kvs", "every-50ms", "127.0.0.1:4009"),
+        ("sled", "never", "127.0.0.1:4008"),
+        ("sled", "every-50ms", "127.0.0.1:4009"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--sync", sync, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key", "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value\n");
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
+    }
+}
+
+// The server should refuse to run with no threads
+#[test]
+fn cli_zero_threads() {
+    for pool in ["naive", "shared", "rayon"] {
+        let temp_dir = TempDir::new().unwrap();
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--pool", pool, "--threads", "0", "--addr", "127.0.0.1:4024"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("NoThreads"));
+    }
+}
+
+// Idle connections should not keep other clients from being answered, however
+// few threads the pool has
+#[test]
+fn cli_idle_connections() {
+    for (pool, addr) in [("shared", "127.0.0.1:4022"), ("rayon", "127.0.0.1:4023")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--pool", pool, "--threads", "2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let mut idle: Vec<KvsClient> = (0..2).map(|_| KvsClient::connect(addr).unwrap()).collect();
+        for client in &mut idle {
+            client.get(b"key".to_vec()).unwrap();
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut client = KvsClient::connect(addr).unwrap();
+            client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+            sender.send(client.get(b"key".to_vec()).unwrap()).unwrap();
+        });
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Some(b"value".to_vec())
+        );
+        drop(idle);
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
+    }
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -226,6 +330,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -269,6 +374,151 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(contains("Key not found"));
 
+    let large_value = "x".repeat(4096);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "large", &large_value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "large", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", large_value));
+
+    // Keys can be set to expire, and their remaining lifetime queried
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "session", "value", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "large", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "missing", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // Binary keys and values can be given in hex or base64
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set", "6b6579ff", "00ff10", "--format", "hex", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "a2V5/w==", "--format", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("AP8Q\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "6b6579", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "not-hex", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // cas only writes when the current value matches
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "counter", "--new", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "counter",
+            "--expected",
+            "5",
+            "--new",
+            "6",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("1\n")
+        .stderr(contains("Value did not match"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "counter",
+            "--expected",
+            "1",
+            "--new",
+            "2",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "counter", "--expected", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "counter", "--expected", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Key not found"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key2", "--addr", addr])
@@ -293,6 +543,38 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key2",
+            "other",
+            "--namespace",
+            "users",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--namespace", "users", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("other\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--namespace", "_users", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid namespace"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -307,6 +589,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -317,6 +600,14 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("value3"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--namespace", "users", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("other\n");
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["get", "key1", "--addr", addr])
@@ -328,6 +619,127 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// `kvs-client scan` should print every pair in the range, across pages
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let pairs: Vec<_> = (0..600)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client
+        .pipeline(
+            pairs
+                .iter()
+                .map(|(key, value)| KvsCommand::Set {
+                    key: key.clone().into_bytes(),
+                    value: value.clone().into_bytes(),
+                })
+                .collect(),
+        )
+        .unwrap();
+    client.set(b"other".to_vec(), b"value".to_vec()).unwrap();
+    drop(client);
+
+    let lines = |args: &[&str]| {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .arg("scan")
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    let expected = |range: std::ops::Range<usize>| -> Vec<String> {
+        pairs[range]
+            .iter()
+            .map(|(key, value)| format!("{}\t{}", key, value))
+            .collect()
+    };
+
+    assert_eq!(lines(&["--prefix", "key"]), expected(0..600));
+    assert_eq!(lines(&["key100", "--end", "key103"]), expected(100..103));
+    assert_eq!(lines(&["key010", "--limit", "300"]), expected(10..310));
+    assert_eq!(lines(&["key599"]), ["key599\tvalue599", "other\tvalue"]);
+    assert_eq!(
+        lines(&["--prefix", "6f74", "--format", "hex"]),
+        [format!(
+            "{}\t{}",
+            hex::encode("other"),
+            hex::encode("value")
+        )]
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to reap server");
+}
+
+// `history` should list the versions of a key, newest first
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set(b"key".to_vec(), b"one".to_vec()).unwrap();
+    client.set(b"key".to_vec(), b"two".to_vec()).unwrap();
+    client.remove(b"key".to_vec()).unwrap();
+    client.set(b"key".to_vec(), b"three".to_vec()).unwrap();
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["history", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("4\tthree\n3\tKey not found\n2\ttwo\n1\tone\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "history", "6b6579", "--limit", "2", "--format", "hex", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("4\t{}\n3\tKey not found\n", hex::encode("three")));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["history", "missing", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to reap server");
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
@@ -0,0 +1,431 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    AsyncKvsClient, CasOutcome, Command as KvsCommand, KvStore, KvsClient, KvsEngine, Result,
+    Version, WriteBatch,
+};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when dropped, including when a test returns early
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().expect("unable to reap server");
+    }
+}
+
+fn spawn_server(temp_dir: &TempDir, addr: &str) -> Server {
+    spawn_server_with(temp_dir, addr, &["--threads", "4"])
+}
+
+fn spawn_server_with(temp_dir: &TempDir, addr: &str, args: &[&str]) -> Server {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Server(child)
+}
+
+// Many requests should be served over the same connection
+#[test]
+fn reuse_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i).into_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    client.remove(b"key0".to_vec())?;
+    assert_eq!(client.get(b"key0".to_vec())?, None);
+    assert!(client.remove(b"key0".to_vec()).is_err());
+
+    // The connection stays usable after an error response
+    assert_eq!(client.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    Ok(())
+}
+
+// Scans should be answered with the pairs in key order
+#[test]
+fn scan_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..10 {
+        client.set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )?;
+    }
+    client.set(b"other".to_vec(), b"value".to_vec())?;
+
+    let pairs = client.scan(b"key2".to_vec(), Some(b"key5".to_vec()), None)?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+            (b"key4".to_vec(), b"value4".to_vec()),
+        ]
+    );
+    assert_eq!(client.scan(Vec::new(), None, Some(4))?.len(), 4);
+    assert_eq!(client.scan_prefix(b"key".to_vec())?.len(), 10);
+    assert_eq!(client.scan_prefix(b"oth".to_vec())?.len(), 1);
+
+    Ok(())
+}
+
+// A batch sent over the connection should be applied as a whole
+#[test]
+fn write_batch_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    client.write_batch(batch)?;
+
+    assert_eq!(client.get(b"key1".to_vec())?, None);
+    assert_eq!(client.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+// A compare-and-swap should report the current value when it does not match
+#[test]
+fn compare_and_swap_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.compare_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        client.compare_and_swap(
+            b"key1".to_vec(),
+            Some(b"other".to_vec()),
+            Some(b"value2".to_vec())
+        )?,
+        CasOutcome::Conflict {
+            current: Some(b"value1".to_vec())
+        }
+    );
+    assert_eq!(
+        client.compare_and_swap(b"key1".to_vec(), Some(b"value1".to_vec()), None)?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(client.get(b"key1".to_vec())?, None);
+
+    Ok(())
+}
+
+// Counters should be updated on the server and report bad values
+#[test]
+fn counters_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.incr(b"counter".to_vec())?, 1);
+    assert_eq!(client.incr_by(b"counter".to_vec(), 41)?, 42);
+    assert_eq!(client.decr(b"counter".to_vec())?, 41);
+    assert_eq!(client.get(b"counter".to_vec())?, Some(b"41".to_vec()));
+
+    client.set(b"text".to_vec(), b"abc".to_vec())?;
+    let error = client.incr(b"text".to_vec()).unwrap_err();
+    assert_eq!(error.to_string(), "Value is not an integer");
+
+    Ok(())
+}
+
+// A transaction over the connection should rerun when a key it read changes
+#[test]
+fn transaction_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set(b"from".to_vec(), b"1".to_vec())?;
+
+    let mut runs = 0;
+    let moved = client.transaction(|tx| {
+        runs += 1;
+        let value = tx.get(b"from".to_vec())?.unwrap();
+        if runs == 1 {
+            other.set(b"from".to_vec(), b"2".to_vec())?;
+        }
+        tx.set(b"to".to_vec(), value.clone());
+        tx.remove(b"from".to_vec());
+        Ok(value)
+    })?;
+    assert_eq!(runs, 2);
+    assert_eq!(moved, b"2".to_vec());
+    assert_eq!(client.get(b"from".to_vec())?, None);
+    assert_eq!(client.get(b"to".to_vec())?, Some(b"2".to_vec()));
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key".to_vec(), b"value".to_vec());
+    assert!(!client.exec(vec![(b"to".to_vec(), None)], batch)?);
+    assert_eq!(client.get(b"key".to_vec())?, None);
+
+    Ok(())
+}
+
+// Past versions of a key should be readable over a connection
+#[test]
+fn history_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set(b"key".to_vec(), b"one".to_vec())?;
+    client.set(b"key".to_vec(), b"two".to_vec())?;
+    client.remove(b"key".to_vec())?;
+
+    assert_eq!(client.get_at(b"key".to_vec(), 1)?, Some(b"one".to_vec()));
+    assert_eq!(client.get_at(b"key".to_vec(), 2)?, Some(b"two".to_vec()));
+    assert_eq!(client.get_at(b"key".to_vec(), 3)?, None);
+    assert_eq!(
+        client.history(b"key".to_vec(), Some(2))?,
+        vec![
+            Version {
+                seq: 3,
+                value: None
+            },
+            Version {
+                seq: 2,
+                value: Some(b"two".to_vec())
+            },
+        ]
+    );
+    assert_eq!(client.history(b"missing".to_vec(), None)?, vec![]);
+
+    Ok(())
+}
+
+// Requests should go to the namespace the client is set to
+#[test]
+fn namespace_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4021";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set(b"key".to_vec(), b"default".to_vec())?;
+    client.set_namespace(Some("users".to_owned()));
+    client.set(b"key".to_vec(), b"user".to_vec())?;
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"user".to_vec()));
+    assert_eq!(client.incr(b"count".to_vec())?, 1);
+
+    let responses = client.pipeline(vec![
+        KvsCommand::Get {
+            key: b"key".to_vec(),
+        },
+        KvsCommand::Get {
+            key: b"key".to_vec(),
+        }
+        .in_namespace("orders"),
+    ])?;
+    let values: Vec<_> = responses
+        .into_iter()
+        .map(|response| response.into_result())
+        .collect::<Result<_>>()?;
+    assert_eq!(values, vec![Some(b"user".to_vec()), None]);
+
+    // Reading from a namespace that does not exist should not create it
+    client.set_namespace(Some("typo".to_owned()));
+    assert_eq!(client.get(b"key".to_vec())?, None);
+    assert!(client.ttl(b"key".to_vec()).is_err());
+    assert_eq!(client.scan(Vec::new(), None, None)?, vec![]);
+    assert_eq!(client.get_at(b"key".to_vec(), 1)?, None);
+    assert_eq!(client.history(b"key".to_vec(), None)?, vec![]);
+    assert!(!temp_dir.path().join("namespaces").join("typo").exists());
+    assert!(!temp_dir.path().join("namespaces").join("orders").exists());
+
+    client.set_namespace(None);
+    assert_eq!(client.get(b"key".to_vec())?, Some(b"default".to_vec()));
+    assert_eq!(client.get(b"count".to_vec())?, None);
+
+    client.set_namespace(Some("not/valid".to_owned()));
+    assert!(client.get(b"key".to_vec()).is_err());
+
+    Ok(())
+}
+
+// Pipelined commands should be answered in the order they were sent
+#[test]
+fn pipeline_commands() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut commands = Vec::new();
+    for i in 0..50 {
+        commands.push(KvsCommand::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+        });
+        commands.push(KvsCommand::Get {
+            key: format!("key{}", i).into_bytes(),
+        });
+    }
+    commands.push(KvsCommand::Remove {
+        key: b"missing".to_vec(),
+    });
+
+    let responses = client.pipeline(commands)?;
+    assert_eq!(responses.len(), 101);
+    for i in 0..50 {
+        assert!(!responses[2 * i].is_error());
+        assert_eq!(
+            responses[2 * i + 1].value,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    assert!(responses[100].is_error());
+
+    Ok(())
+}
+
+// An idle connection should not block other clients
+#[test]
+fn concurrent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let _server = spawn_server(&temp_dir, addr);
+
+    let _idle = KvsClient::connect(addr)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for i in 0..20 {
+                    let key = format!("key{}-{}", t, i).into_bytes();
+                    client.set(key.clone(), format!("value{}", i).into_bytes())?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i).into_bytes()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// The async client should work against the tokio runtime
+#[test]
+fn async_client_tokio_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let _server = spawn_server_with(&temp_dir, addr, &["--runtime", "tokio"]);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+        assert_eq!(
+            client.get(b"key1".to_vec()).await?,
+            Some(b"value1".to_vec())
+        );
+        client.remove(b"key1".to_vec()).await?;
+        assert_eq!(client.get(b"key1".to_vec()).await?, None);
+        assert!(client.remove(b"key1".to_vec()).await.is_err());
+
+        let responses = client
+            .pipeline(vec![
+                KvsCommand::Set {
+                    key: b"key2".to_vec(),
+                    value: b"value2".to_vec(),
+                },
+                KvsCommand::Get {
+                    key: b"key2".to_vec(),
+                },
+            ])
+            .await?;
+        assert_eq!(responses[1].value, Some(b"value2".to_vec()));
+
+        Ok(())
+    })
+}
+
+// Idle connections should not tie up the tokio runtime's single thread
+#[test]
+fn tokio_server_many_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let _server = spawn_server_with(&temp_dir, addr, &["--runtime", "tokio", "--threads", "1"]);
+
+    let idle: Vec<_> = (0..200).map(|_| KvsClient::connect(addr)).collect();
+    assert!(idle.iter().all(|client| client.is_ok()));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(client.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    Ok(())
+}
+
+// A response too large for a frame should come back as an error, leaving the
+// connection usable
+#[test]
+fn oversized_response() -> Result<()> {
+    for (runtime, addr) in [("threads", "127.0.0.1:4025"), ("tokio", "127.0.0.1:4026")] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..66 {
+            store.set(format!("key{:02}", i).into_bytes(), vec![b'v'; 1024 * 1024])?;
+        }
+        drop(store);
+        let _server = spawn_server_with(&temp_dir, addr, &["--runtime", runtime]);
+
+        let mut client = KvsClient::connect(addr)?;
+        let error = client.scan(Vec::new(), None, None).err().unwrap();
+        assert!(error.to_string().contains("exceeds"), "{}", error);
+        assert_eq!(client.scan(Vec::new(), None, Some(2))?.len(), 2);
+        assert_eq!(
+            client.get(b"key00".to_vec())?,
+            Some(vec![b'v'; 1024 * 1024])
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use kvs::codec::{self, PROTOCOL_VERSION};
+use kvs::{Command, Response, Result};
+use std::io::Cursor;
+
+// A command larger than the old 128 byte read buffer should survive a round trip
+#[test]
+fn round_trip_large_command() -> Result<()> {
+    let value = vec![b'v'; 10_000];
+    let mut buffer = Vec::new();
+
+    codec::write_frame(
+        &mut buffer,
+        &Command::Set {
+            key: b"key1".to_vec(),
+            value: value.clone(),
+        },
+    )?;
+
+    match codec::read_frame(&mut Cursor::new(buffer))? {
+        Command::Set { key, value: read } => {
+            assert_eq!(key, b"key1");
+            assert_eq!(read, value);
+        }
+        command => panic!("unexpected command {:?}", command),
+    }
+
+    Ok(())
+}
+
+// Several frames written back to back should be read one at a time
+#[test]
+fn read_consecutive_frames() -> Result<()> {
+    let mut buffer = Vec::new();
+
+    codec::write_frame(&mut buffer, &Response::new(Ok(Some(b"a".to_vec()))))?;
+    codec::write_frame(&mut buffer, &Response::new(Ok(None)))?;
+
+    let mut reader = Cursor::new(buffer);
+    let first: Response = codec::read_frame(&mut reader)?;
+    let second: Response = codec::read_frame(&mut reader)?;
+
+    assert_eq!(first.value, Some(b"a".to_vec()));
+    assert_eq!(second.value, None);
+
+    Ok(())
+}
+
+// Keys and values that are not valid UTF-8 should survive a round trip
+#[test]
+fn round_trip_binary_command() -> Result<()> {
+    let mut buffer = Vec::new();
+
+    codec::write_frame(
+        &mut buffer,
+        &Command::Set {
+            key: vec![0, 159, 146, 150],
+            value: vec![0xff; 3],
+        },
+    )?;
+
+    match codec::read_frame(&mut Cursor::new(buffer))? {
+        Command::Set { key, value } => {
+            assert_eq!(key, [0, 159, 146, 150]);
+            assert_eq!(value, [0xff; 3]);
+        }
+        command => panic!("unexpected command {:?}", command),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_version() {
+    let mut frame = vec![PROTOCOL_VERSION + 1];
+    frame.extend_from_slice(&2u32.to_be_bytes());
+    frame.extend_from_slice(b"{}");
+
+    let result: Result<Response> = codec::read_frame(&mut Cursor::new(frame));
+    assert!(result.is_err());
+}
+
+#[test]
+fn reject_oversized_frame() {
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend_from_slice(&u32::MAX.to_be_bytes());
+
+    let result: Result<Response> = codec::read_frame(&mut Cursor::new(frame));
+    assert!(result.is_err());
+}
+
+#[test]
+fn reject_truncated_frame() {
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend_from_slice(&100u32.to_be_bytes());
+    frame.extend_from_slice(b"{\"Get\"");
+
+    let result: Result<Command> = codec::read_frame(&mut Cursor::new(frame));
+    assert!(result.is_err());
+}
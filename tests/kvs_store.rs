@@ -1,4 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    prefix_end, CachedEngine, CasOutcome, CorruptRecord, CorruptValue, CounterError, Durability,
+    InvalidNamespace, KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, NamespaceInUse, Result,
+    SledKvStore, Unsupported, Version, WriteBatch,
+};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,19 +18,19 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -27,100 +39,2274 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
 
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key
+fn binary_keys_and_values<E: KvsEngine>(store: E) -> Result<()> {
+    let key = vec![0, 159, 146, 150];
+    let value = vec![b'\n', 0xff, 0, b'\r'];
+    store.set(key.clone(), value.clone())?;
+    store.set(vec![], vec![0xfe])?;
+    assert_eq!(store.get(key.clone())?, Some(value));
+    assert_eq!(store.get(vec![])?, Some(vec![0xfe]));
+    store.remove(key.clone())?;
+    assert_eq!(store.get(key)?, None);
+
+    Ok(())
+}
+
+// Keys and values that are not valid UTF-8 should be stored as they are
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn binary_keys_and_values_kvs() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    binary_keys_and_values(KvStore::open(temp_dir.path())?)?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(vec![])?, Some(vec![0xfe]));
 
-    // Open from disk again and check persistent data
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(SledKvStore::open(temp_dir.path())?)
+}
+
+fn scan_ranges<E: KvsEngine>(store: E) -> Result<()> {
+    for key_id in 0..20 {
+        store.set(
+            format!("key{:02}", key_id).into_bytes(),
+            format!("value{}", key_id).into_bytes(),
+        )?;
+    }
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+    store.remove(b"key05".to_vec())?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+        pairs
+            .into_iter()
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    };
+
+    let pairs = store.scan(b"key03".to_vec(), Some(b"key07".to_vec()), None)?;
+    assert_eq!(pairs[0], (b"key03".to_vec(), b"value3".to_vec()));
+    assert_eq!(keys(pairs), ["key03", "key04", "key06"]);
+
+    let pairs = store.scan(b"key18".to_vec(), None, None)?;
+    assert_eq!(keys(pairs), ["key18", "key19", "other"]);
+
+    let pairs = store.scan(Vec::new(), None, Some(2))?;
+    assert_eq!(keys(pairs), ["key00", "key01"]);
+
+    assert!(store
+        .scan(b"key07".to_vec(), Some(b"key03".to_vec()), None)?
+        .is_empty());
+
+    let pairs = store.scan_prefix(b"key1".to_vec())?;
+    assert_eq!(pairs.len(), 10);
+    assert_eq!(pairs[9], (b"key19".to_vec(), b"value19".to_vec()));
+
+    assert_eq!(store.scan_prefix(Vec::new())?.len(), 20);
+    assert!(store.scan_prefix(b"missing".to_vec())?.is_empty());
+
+    Ok(())
+}
+
+// Scans should return live pairs in key order
+#[test]
+fn scan_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_ranges(KvStore::open(temp_dir.path())?)?;
+
+    // The order should survive compaction and reopening
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction()?;
+    let pairs = store.scan(b"key04".to_vec(), Some(b"key07".to_vec()), None)?;
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(pairs[1], (b"key06".to_vec(), b"value6".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_ranges(SledKvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn prefix_end_bound() {
+    assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+    assert_eq!(prefix_end(&[b'a', 0xff]), Some(b"b".to_vec()));
+    assert_eq!(prefix_end(&[0xff, 0xff]), None);
+    assert_eq!(prefix_end(b""), None);
+}
+
+fn expiring_keys<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set_with_ttl(
+        b"short".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        b"long".to_vec(),
+        b"value".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    store.set(b"forever".to_vec(), b"value".to_vec())?;
+
+    assert_eq!(store.get(b"short".to_vec())?, Some(b"value".to_vec()));
+    let ttl = store.ttl(b"long".to_vec())?.expect("missing ttl");
+    assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+    assert_eq!(store.ttl(b"forever".to_vec())?, None);
+    assert!(store.ttl(b"missing".to_vec()).is_err());
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert!(store.ttl(b"short".to_vec()).is_err());
+    assert_eq!(store.scan(Vec::new(), None, None)?.len(), 2);
+    assert!(store.remove(b"short".to_vec()).is_err());
+    assert_eq!(store.get(b"long".to_vec())?, Some(b"value".to_vec()));
+
+    // Setting a key again without a TTL clears its expiry
+    store.set_with_ttl(
+        b"again".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    store.set(b"again".to_vec(), b"value".to_vec())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get(b"again".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.ttl(b"again".to_vec())?, None);
+
+    Ok(())
+}
+
+// Keys set with a TTL should read as missing once it has passed
+#[test]
+fn expire_keys_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiring_keys(&KvStore::open(temp_dir.path())?)?;
+
+    // Expiry times should be persisted, and expired keys dropped by compaction
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        b"short".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    store.compaction()?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert!(store.ttl(b"long".to_vec())?.is_some());
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert_eq!(store.get(b"long".to_vec())?, Some(b"value".to_vec()));
+    assert!(store.ttl(b"long".to_vec())?.is_some());
+    assert_eq!(store.ttl(b"forever".to_vec())?, None);
 
     Ok(())
 }
 
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn expire_keys_sled() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    expiring_keys(&SledKvStore::open(temp_dir.path())?)?;
+
+    let store = SledKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"short".to_vec())?, None);
+    assert!(store.ttl(b"long".to_vec())?.is_some());
+
+    Ok(())
+}
+
+fn write_batches<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(60),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"batch2".to_vec());
+    batch.set(b"key3".to_vec(), b"batch3".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.remove(b"missing".to_vec());
+    batch.set(b"key4".to_vec(), b"first".to_vec());
+    batch.set(b"key4".to_vec(), b"second".to_vec());
+    assert_eq!(batch.len(), 6);
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"batch2".to_vec()));
+    assert_eq!(store.ttl(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"batch3".to_vec()));
+    assert_eq!(store.get(b"key4".to_vec())?, Some(b"second".to_vec()));
+
+    store.write_batch(WriteBatch::new())?;
+
     Ok(())
 }
 
+// A batch should apply all of its operations
 #[test]
-fn remove_key() -> Result<()> {
+fn write_batch_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batches(&KvStore::open(temp_dir.path())?)?;
+
+    // Records inside a batch should survive reopening and compaction
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key4".to_vec())?, Some(b"second".to_vec()));
+    store.compaction()?;
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"batch3".to_vec()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"batch2".to_vec()));
+    assert_eq!(store.get(b"key4".to_vec())?, Some(b"second".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_sled() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    write_batches(&SledKvStore::open(temp_dir.path())?)
+}
+
+fn compare_and_swaps<E: KvsEngine>(store: &E) -> Result<()> {
+    let key = || b"key1".to_vec();
+
+    assert_eq!(
+        store.compare_and_swap(key(), None, Some(b"value1".to_vec()))?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        store.compare_and_swap(key(), None, Some(b"other".to_vec()))?,
+        CasOutcome::Conflict {
+            current: Some(b"value1".to_vec())
+        }
+    );
+    assert_eq!(
+        store.compare_and_swap(key(), Some(b"value1".to_vec()), Some(b"value2".to_vec()))?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(store.get(key())?, Some(b"value2".to_vec()));
+
+    // A None new value removes the key
+    assert!(store
+        .compare_and_swap(key(), Some(b"value2".to_vec()), None)?
+        .is_swapped());
+    assert_eq!(store.get(key())?, None);
+    assert_eq!(
+        store.compare_and_swap(key(), Some(b"value2".to_vec()), None)?,
+        CasOutcome::Conflict { current: None }
+    );
+
+    // An expired key counts as missing, and a swapped key no longer expires
+    store.set_with_ttl(key(), b"old".to_vec(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        store.compare_and_swap(key(), Some(b"old".to_vec()), None)?,
+        CasOutcome::Conflict { current: None }
+    );
+    assert!(store
+        .compare_and_swap(key(), None, Some(b"new".to_vec()))?
+        .is_swapped());
+    store.set_with_ttl(key(), b"ttl".to_vec(), Duration::from_secs(60))?;
+    assert!(store
+        .compare_and_swap(key(), Some(b"ttl".to_vec()), Some(b"kept".to_vec()))?
+        .is_swapped());
+    assert_eq!(store.ttl(key())?, None);
+
+    // Concurrent increments should never lose an update
+    store.set(b"counter".to_vec(), b"0".to_vec())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get(b"counter".to_vec()).unwrap().unwrap();
+                        let next: u64 = String::from_utf8(current.clone())
+                            .unwrap()
+                            .parse::<u64>()
+                            .unwrap()
+                            + 1;
+                        let outcome = store
+                            .compare_and_swap(
+                                b"counter".to_vec(),
+                                Some(current),
+                                Some(next.to_string().into_bytes()),
+                            )
+                            .unwrap();
+                        if outcome.is_swapped() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"200".to_vec()));
+
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// A compare-and-swap should only write when the current value matches
 #[test]
-fn compaction() -> Result<()> {
+fn compare_and_swap_kvs() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    compare_and_swaps(&KvStore::open(temp_dir.path())?)?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"kept".to_vec()));
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"200".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swaps(&SledKvStore::open(temp_dir.path())?)
+}
+
+fn counters<E: KvsEngine>(store: &E) -> Result<()> {
+    let key = || b"counter".to_vec();
+
+    assert_eq!(store.incr(key())?, 1);
+    assert_eq!(store.incr_by(key(), 10)?, 11);
+    assert_eq!(store.decr(key())?, 10);
+    assert_eq!(store.incr_by(key(), -15)?, -5);
+    assert_eq!(store.get(key())?, Some(b"-5".to_vec()));
+    assert_eq!(store.decr(b"fresh".to_vec())?, -1);
+
+    store.set(b"text".to_vec(), b"abc".to_vec())?;
+    match store.incr(b"text".to_vec()) {
+        Err(e) => assert!(matches!(
+            e.downcast_ref::<CounterError>(),
+            Some(CounterError::NotAnInteger)
+        )),
+        Ok(_) => panic!("incremented a non-numeric value"),
+    }
+    assert_eq!(store.get(b"text".to_vec())?, Some(b"abc".to_vec()));
+
+    store.set(b"max".to_vec(), i64::MAX.to_string().into_bytes())?;
+    match store.incr(b"max".to_vec()) {
+        Err(e) => assert!(matches!(
+            e.downcast_ref::<CounterError>(),
+            Some(CounterError::Overflow)
+        )),
+        Ok(_) => panic!("incremented past i64::MAX"),
+    }
+
+    // A counter keeps its expiry time, and an expired one starts from 0
+    store.set_with_ttl(b"session".to_vec(), b"5".to_vec(), Duration::from_secs(60))?;
+    assert_eq!(store.incr(b"session".to_vec())?, 6);
+    assert!(store.ttl(b"session".to_vec())?.is_some());
+    store.set_with_ttl(b"short".to_vec(), b"5".to_vec(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.incr(b"short".to_vec())?, 1);
+    assert_eq!(store.ttl(b"short".to_vec())?, None);
+
+    // Concurrent increments should never lose an update
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    store.incr(b"shared".to_vec()).unwrap();
+                }
             })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(b"shared".to_vec())?, Some(b"200".to_vec()));
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+    Ok(())
+}
+
+// Counters should be updated atomically and reject non-numeric values
+#[test]
+fn counters_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"-5".to_vec()));
+    assert_eq!(store.incr(b"shared".to_vec())?, 201);
+
+    Ok(())
+}
+
+#[test]
+fn counters_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(&SledKvStore::open(temp_dir.path())?)
+}
+
+fn transactions<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set(b"a".to_vec(), b"100".to_vec())?;
+    store.set(b"b".to_vec(), b"0".to_vec())?;
+
+    // Concurrent transfers should never create or lose money
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    store
+                        .transaction(|tx| {
+                            let a: i64 =
+                                String::from_utf8(tx.get(b"a".to_vec())?.unwrap())?.parse()?;
+                            let b: i64 =
+                                String::from_utf8(tx.get(b"b".to_vec())?.unwrap())?.parse()?;
+                            tx.set(b"a".to_vec(), (a - 1).to_string().into_bytes());
+                            tx.set(b"b".to_vec(), (b + 1).to_string().into_bytes());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"60".to_vec()));
+    assert_eq!(store.get(b"b".to_vec())?, Some(b"40".to_vec()));
+
+    // A transaction sees its own writes, and returns what its closure did
+    let value = store.transaction(|tx| {
+        tx.set(b"c".to_vec(), b"value".to_vec());
+        tx.remove(b"a".to_vec());
+        assert_eq!(tx.get(b"a".to_vec())?, None);
+        tx.get(b"c".to_vec())
+    })?;
+    assert_eq!(value, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"a".to_vec())?, None);
+
+    // A transaction reruns when a key it read changes before it commits
+    let mut runs = 0;
+    store.transaction(|tx| {
+        runs += 1;
+        let b = tx.get(b"b".to_vec())?;
+        if runs == 1 {
+            store.set(b"b".to_vec(), b"changed".to_vec())?;
         }
+        tx.set(b"d".to_vec(), b.unwrap());
+        Ok(())
+    })?;
+    assert_eq!(runs, 2);
+    assert_eq!(store.get(b"d".to_vec())?, Some(b"changed".to_vec()));
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    // An error from the closure writes nothing
+    let result: Result<()> = store.transaction(|tx| {
+        tx.set(b"e".to_vec(), b"value".to_vec());
+        Err(failure::err_msg("abort"))
+    });
+    assert!(result.is_err());
+    assert_eq!(store.get(b"e".to_vec())?, None);
+
+    // commit_batch only applies when the watched values still match
+    let mut batch = WriteBatch::new();
+    batch.set(b"f".to_vec(), b"value".to_vec());
+    assert!(!store.commit_batch(vec![(b"c".to_vec(), None)], batch.clone())?);
+    assert_eq!(store.get(b"f".to_vec())?, None);
+    assert!(store.commit_batch(
+        vec![
+            (b"c".to_vec(), Some(b"value".to_vec())),
+            (b"a".to_vec(), None)
+        ],
+        batch
+    )?);
+    assert_eq!(store.get(b"f".to_vec())?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// Transactions should apply atomically and retry on conflicts
+#[test]
+fn transactions_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions(&KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"b".to_vec())?, Some(b"changed".to_vec()));
+    assert_eq!(store.get(b"f".to_vec())?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn transactions_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions(&SledKvStore::open(temp_dir.path())?)
+}
+
+fn snapshots<E: KvsEngine>(store: &E) -> Result<()> {
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.set_with_ttl(
+        b"session".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+
+    let snapshot = store.snapshot()?;
+
+    store.set(b"key1".to_vec(), b"changed".to_vec())?;
+    store.remove(b"key2".to_vec())?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    thread::sleep(Duration::from_millis(200));
+
+    // Writes and expiry after the snapshot was taken should not show up in it
+    assert_eq!(snapshot.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(snapshot.get(b"key3".to_vec())?, None);
+    assert_eq!(snapshot.get(b"session".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(
+        snapshot.scan_prefix(b"key".to_vec())?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    assert_eq!(
+        snapshot.scan(b"key2".to_vec(), None, Some(1))?,
+        vec![(b"key2".to_vec(), b"value2".to_vec())]
+    );
+
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"changed".to_vec()));
+    assert_eq!(store.get(b"session".to_vec())?, None);
+
+    Ok(())
+}
+
+// A snapshot should keep reading the store as it was when it was taken
+#[test]
+fn snapshot_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshots(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshots(&SledKvStore::open(temp_dir.path())?)
+}
+
+// Writes to a large sled store should go on while a snapshot copies it, and
+// should not show up in it
+#[test]
+fn snapshot_large_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open_with(temp_dir.path(), Durability::Never)?;
+    let key = |key_id: usize| format!("key{:05}", key_id).into_bytes();
+    let mut original = Vec::new();
+    for chunk in 0..50 {
+        let mut batch = WriteBatch::new();
+        for key_id in chunk * 1000..(chunk + 1) * 1000 {
+            let value = format!("value{}", key_id).into_bytes();
+            batch.set(key(key_id), value.clone());
+            original.push((key(key_id), value));
         }
-        // Compaction triggered
+        store.write_batch(batch)?;
+    }
+
+    // Each step changes one key, removes the next and adds a new one, all at
+    // once, so the snapshot should hold the keys as of some number of steps
+    let step = move |key_id: usize| {
+        let mut batch = WriteBatch::new();
+        batch.set(key(key_id), b"changed".to_vec());
+        batch.remove(key(key_id + 1));
+        batch.set(format!("new{:05}", key_id).into_bytes(), b"value".to_vec());
+        batch
+    };
+    let done = Arc::new(AtomicBool::new(false));
+    let steps = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        let steps = Arc::clone(&steps);
+        thread::spawn(move || -> Result<()> {
+            let mut key_id = 0;
+            while !done.load(Ordering::SeqCst) && key_id < 50_000 {
+                store.write_batch(step(key_id))?;
+                steps.fetch_add(1, Ordering::SeqCst);
+                key_id += 2;
+            }
+            Ok(())
+        })
+    };
+
+    while steps.load(Ordering::SeqCst) == 0 {
+        thread::yield_now();
+    }
+    let before = steps.load(Ordering::SeqCst);
+    let snapshot = store.snapshot()?;
+    let during = steps.load(Ordering::SeqCst) - before;
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap()?;
+    assert!(
+        during > 10,
+        "only {} writes made it in during the copy",
+        during
+    );
+
+    let taken = snapshot.scan_prefix(b"new".to_vec())?.len();
+    assert!(taken >= before && taken <= before + during);
+    let mut expected: BTreeMap<_, _> = original.into_iter().collect();
+    for key_id in (0..taken).map(|step_id| step_id * 2) {
+        expected.insert(key(key_id), b"changed".to_vec());
+        expected.remove(&key(key_id + 1));
+        expected.insert(format!("new{:05}", key_id).into_bytes(), b"value".to_vec());
+    }
+    assert_eq!(
+        snapshot.scan(Vec::new(), None, None)?,
+        expected.into_iter().collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+// Compaction should keep the generations a snapshot reads from until it is dropped
+#[test]
+fn snapshot_outlives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    let snapshot = store.snapshot()?;
+    let other = store.snapshot()?;
+    store.set(b"key1".to_vec(), b"changed".to_vec())?;
+    store.remove(b"key2".to_vec())?;
+
+    store.compaction()?;
+    assert_eq!(log_files(&temp_dir), vec!["1.log", "2.log", "3.log"]);
+    assert_eq!(snapshot.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"changed".to_vec()));
+
+    // Snapshots taken after compaction only hold on to the newer generations
+    let newer = store.snapshot()?;
+    drop(snapshot);
+    assert_eq!(log_files(&temp_dir), vec!["1.log", "2.log", "3.log"]);
+    drop(other);
+    assert_eq!(log_files(&temp_dir), vec!["2.log", "3.log"]);
+    assert_eq!(newer.get(b"key1".to_vec())?, Some(b"changed".to_vec()));
+    assert_eq!(newer.get(b"key2".to_vec())?, None);
+
+    store.compaction()?;
+    assert_eq!(
+        log_files(&temp_dir),
+        vec!["2.log", "3.log", "4.log", "5.log"]
+    );
+    drop(newer);
+    assert_eq!(log_files(&temp_dir), vec!["4.log", "5.log"]);
+
+    Ok(())
+}
+
+fn namespaces<E: KvsEngine>(store: &E) -> Result<()> {
+    let users = store.namespace("users")?;
+    let orders = users.namespace("orders")?;
+
+    store.set(b"key".to_vec(), b"default".to_vec())?;
+    users.set(b"key".to_vec(), b"user".to_vec())?;
+    users.set_with_ttl(
+        b"session".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    orders.set(b"order".to_vec(), b"value".to_vec())?;
+
+    // Each namespace should only see its own keys
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"default".to_vec()));
+    assert_eq!(users.get(b"key".to_vec())?, Some(b"user".to_vec()));
+    assert_eq!(orders.get(b"key".to_vec())?, None);
+    assert_eq!(store.get(b"order".to_vec())?, None);
+    assert_eq!(
+        orders.scan(Vec::new(), None, None)?,
+        vec![(b"order".to_vec(), b"value".to_vec())]
+    );
+    assert!(users.ttl(b"session".to_vec())?.is_some());
+    assert!(store.ttl(b"session".to_vec()).is_err());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(users.get(b"session".to_vec())?, None);
+
+    // Another handle to a namespace should see the same keys
+    let again = store.namespace("users")?;
+    again.set(b"other".to_vec(), b"value".to_vec())?;
+    assert_eq!(users.get(b"other".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(
+        again.snapshot()?.get(b"key".to_vec())?,
+        Some(b"user".to_vec())
+    );
+
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+
+    // Looking up a namespace should only find ones that exist
+    assert_eq!(
+        store
+            .existing_namespace("users")?
+            .unwrap()
+            .get(b"key".to_vec())?,
+        Some(b"user".to_vec())
+    );
+    assert!(store.existing_namespace("typo")?.is_none());
+    assert!(store.existing_namespace("_kvs").is_err());
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+
+    for name in ["", "_kvs", "a/b", "..", "white space"] {
+        let error = store.namespace(name).err().unwrap();
+        assert!(error.downcast_ref::<InvalidNamespace>().is_some());
+    }
+
+    // A namespace should not be dropped while a handle to it is in use
+    let error = store.drop_namespace("users").err().unwrap();
+    assert!(error.downcast_ref::<NamespaceInUse>().is_some());
+    users.set(b"later".to_vec(), b"value".to_vec())?;
+    assert_eq!(again.get(b"later".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+    drop(users);
+    drop(again);
+
+    // Dropping a namespace should delete its keys and nothing else
+    assert!(store.drop_namespace("users")?);
+    assert!(!store.drop_namespace("users")?);
+    assert_eq!(store.namespaces()?, vec!["orders"]);
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"default".to_vec()));
+    assert_eq!(orders.get(b"order".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.namespace("users")?.get(b"key".to_vec())?, None);
+
+    Ok(())
+}
+
+// Namespaces should be separate keyspaces that can be created and dropped
+#[test]
+fn namespaces_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    namespaces(&store)?;
+
+    // Each namespace is compacted on its own
+    let orders = store.namespace("orders")?;
+    orders.set(b"order".to_vec(), b"changed".to_vec())?;
+    orders.compaction()?;
+    assert_eq!(orders.compaction_stats().runs, 1);
+    assert_eq!(store.compaction_stats().runs, 0);
+
+    drop(orders);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+    assert_eq!(
+        store.namespace("orders")?.get(b"order".to_vec())?,
+        Some(b"changed".to_vec())
+    );
+    assert_eq!(store.get(b"order".to_vec())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn namespaces_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    namespaces(&store)?;
+
+    drop(store);
+    let store = SledKvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, vec!["orders", "users"]);
+    assert_eq!(
+        store.namespace("orders")?.get(b"order".to_vec())?,
+        Some(b"value".to_vec())
+    );
+
+    Ok(())
+}
+
+const DURABILITIES: [Durability; 3] = [
+    Durability::Never,
+    Durability::EveryWrite,
+    Durability::Interval(Duration::from_millis(10)),
+];
+
+// Writers on many threads at once should all get their writes in, whether
+// or not they share syncs
+fn concurrent_writes<E: KvsEngine>(store: &E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", thread, i).into_bytes();
+                    store.set(key.clone(), format!("value{}", i).into_bytes())?;
+                    if i % 5 == 0 {
+                        store.remove(key)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    check_concurrent_writes(store)
+}
+
+fn check_concurrent_writes<E: KvsEngine>(store: &E) -> Result<()> {
+    for thread in 0..8 {
+        for i in 0..50 {
+            let value = store.get(format!("key{}-{}", thread, i).into_bytes())?;
+            match i % 5 {
+                0 => assert_eq!(value, None),
+                _ => assert_eq!(value, Some(format!("value{}", i).into_bytes())),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn durability_kvs() -> Result<()> {
+    for durability in DURABILITIES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .durability(durability)
+            .compaction_threshold(4 * 1024);
+        let store = options.open(temp_dir.path())?;
+        concurrent_writes(&store)?;
+        store.compaction()?;
+        check_concurrent_writes(&store)?;
 
         drop(store);
-        // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        check_concurrent_writes(&options.open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn durability_sled() -> Result<()> {
+    for durability in DURABILITIES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        concurrent_writes(&SledKvStore::open_with(temp_dir.path(), durability)?)?;
+        check_concurrent_writes(&reopen_sled(temp_dir.path(), durability)?)?;
+    }
+
+    Ok(())
+}
+
+// sled's threads let go of the database, and of its lock on the directory,
+// shortly after the last handle is dropped rather than at once
+fn reopen_sled(path: &Path, durability: Durability) -> Result<SledKvStore> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+
+    loop {
+        match SledKvStore::open_with(path, durability) {
+            Err(e) if sled_locked(&e) && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => return result,
         }
-        return Ok(());
     }
+}
 
-    panic!("No compaction detected");
+// sled reports a directory that is still locked as a plain I/O error
+fn sled_locked(error: &failure::Error) -> bool {
+    match error.downcast_ref::<sled::Error>() {
+        Some(sled::Error::Io(e)) => e.to_string().starts_with("could not acquire lock"),
+        _ => false,
+    }
+}
+
+fn version(seq: u64, value: Option<&[u8]>) -> Version {
+    Version {
+        seq,
+        value: value.map(<[u8]>::to_vec),
+    }
+}
+
+// Every write should get a sequence number, and past versions should be
+// readable until compaction reclaims them
+#[test]
+fn history_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || b"key".to_vec();
+
+    store.set(key(), b"one".to_vec())?;
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+    store.set(key(), b"two".to_vec())?;
+    store.remove(key())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"other".to_vec(), b"batch".to_vec());
+    batch.set(key(), b"three".to_vec());
+    store.write_batch(batch)?;
+
+    let expected = vec![
+        version(6, Some(b"three")),
+        version(4, None),
+        version(3, Some(b"two")),
+        version(1, Some(b"one")),
+    ];
+    assert_eq!(store.history(key(), None)?, expected);
+    assert_eq!(store.history(key(), Some(2))?, expected[..2]);
+    assert_eq!(store.history(b"missing".to_vec(), None)?, vec![]);
+    assert_eq!(store.get_at(key(), 0)?, None);
+    assert_eq!(store.get_at(key(), 1)?, Some(b"one".to_vec()));
+    assert_eq!(store.get_at(key(), 2)?, Some(b"one".to_vec()));
+    assert_eq!(store.get_at(key(), 3)?, Some(b"two".to_vec()));
+    assert_eq!(store.get_at(key(), 4)?, None);
+    assert_eq!(store.get_at(key(), 5)?, None);
+    assert_eq!(store.get_at(key(), 100)?, Some(b"three".to_vec()));
+    assert_eq!(store.get_at(b"other".to_vec(), 4)?, Some(b"value".to_vec()));
+
+    // Sequence numbers and history should survive reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history(key(), None)?, expected);
+    store.set(key(), b"four".to_vec())?;
+    assert_eq!(
+        store.history(key(), Some(1))?,
+        vec![version(7, Some(b"four"))]
+    );
+
+    // Compaction reclaims past versions by default
+    store.compaction()?;
+    assert_eq!(store.history(key(), None)?, vec![version(7, Some(b"four"))]);
+    assert_eq!(store.get_at(key(), 3)?, None);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history(key(), None)?, vec![version(7, Some(b"four"))]);
+    store.set(key(), b"five".to_vec())?;
+    assert_eq!(
+        store.history(key(), None)?,
+        vec![version(8, Some(b"five")), version(7, Some(b"four"))]
+    );
+
+    Ok(())
+}
+
+// Sequence numbers should keep increasing after compaction drops the records
+// that had the highest ones, with or without hints
+#[test]
+fn sequence_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"value".to_vec())?;
+    store.set(b"b".to_vec(), b"value".to_vec())?;
+    store.set(b"b".to_vec(), b"value".to_vec())?;
+    store.remove(b"b".to_vec())?;
+    store.compaction()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"c".to_vec(), b"value".to_vec())?;
+    assert_eq!(
+        store.history(b"c".to_vec(), None)?,
+        vec![version(5, Some(b"value"))]
+    );
+    assert_eq!(store.get_at(b"c".to_vec(), 4)?, None);
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("2.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"d".to_vec(), b"value".to_vec())?;
+    assert_eq!(
+        store.history(b"d".to_vec(), None)?,
+        vec![version(6, Some(b"value"))]
+    );
+
+    // Even when compaction leaves nothing at all behind
+    store.remove(b"a".to_vec())?;
+    store.remove(b"c".to_vec())?;
+    store.remove(b"d".to_vec())?;
+    store.compaction()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"e".to_vec(), b"value".to_vec())?;
+    assert_eq!(
+        store.history(b"e".to_vec(), None)?,
+        vec![version(10, Some(b"value"))]
+    );
+
+    Ok(())
+}
+
+// Compaction should keep as many past versions of each key as configured
+#[test]
+fn retain_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().retained_versions(2);
+    let store = options.open(temp_dir.path())?;
+
+    for i in 1..=5 {
+        store.set(b"key".to_vec(), format!("value{}", i).into_bytes())?;
+    }
+    store.set(b"removed".to_vec(), b"value".to_vec())?;
+    store.remove(b"removed".to_vec())?;
+    store.set_with_ttl(
+        b"expired".to_vec(),
+        b"old".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    store.set_with_ttl(
+        b"expired".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(
+            store.history(b"key".to_vec(), None)?,
+            vec![
+                version(5, Some(b"value5")),
+                version(4, Some(b"value4")),
+                version(3, Some(b"value3")),
+            ]
+        );
+        assert_eq!(store.get_at(b"key".to_vec(), 4)?, Some(b"value4".to_vec()));
+        assert_eq!(store.get_at(b"key".to_vec(), 2)?, None);
+        assert_eq!(store.get(b"removed".to_vec())?, None);
+        assert_eq!(
+            store.history(b"removed".to_vec(), None)?,
+            vec![version(7, None), version(6, Some(b"value"))]
+        );
+        assert_eq!(store.get(b"expired".to_vec())?, None);
+        assert_eq!(
+            store.history(b"expired".to_vec(), None)?,
+            vec![version(9, None), version(8, Some(b"old"))]
+        );
+        Ok(())
+    };
+
+    store.compaction()?;
+    check(&store)?;
+    drop(store);
+
+    // Removed and expired keys should stay gone after replaying what was kept
+    let store = options.open(temp_dir.path())?;
+    check(&store)?;
+    store.compaction()?;
+    check(&store)?;
+    store.set(b"key".to_vec(), b"value6".to_vec())?;
+    assert_eq!(store.history(b"key".to_vec(), None)?.len(), 4);
+    store.compaction()?;
+    assert_eq!(
+        store.history(b"key".to_vec(), None)?,
+        vec![
+            version(10, Some(b"value6")),
+            version(5, Some(b"value5")),
+            version(4, Some(b"value4")),
+        ]
+    );
+
+    Ok(())
+}
+
+// An expired key that hides no older version should be dropped by the first
+// compaction rather than copied by every one after it
+#[test]
+fn compact_expired_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().retained_versions(1);
+    let store = options.open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        b"expired".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    thread::sleep(Duration::from_millis(200));
+
+    let mut written = Vec::new();
+    for _ in 0..3 {
+        store.compaction()?;
+        written.push(store.compaction_stats().last_run.unwrap().bytes_written);
+        assert_eq!(store.history(b"expired".to_vec(), None)?, vec![]);
+    }
+    // Only the sequence mark, a header and sequence number, then the record
+    // of key: its header, sequence number, key and value
+    let len = 13 + 8 + 13 + 8 + "key".len() as u64 + "value".len() as u64;
+    assert_eq!(written, vec![len; 3]);
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    store.compaction()?;
+    assert_eq!(
+        store.compaction_stats().last_run.unwrap().bytes_written,
+        len
+    );
+    assert_eq!(store.history(b"expired".to_vec(), None)?, vec![]);
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// The sled engine overwrites in place and keeps no history
+#[test]
+fn history_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+
+    let error = store.history(b"key".to_vec(), None).unwrap_err();
+    assert!(error.downcast_ref::<Unsupported>().is_some());
+    let error = store.get_at(b"key".to_vec(), 1).unwrap_err();
+    assert!(error.downcast_ref::<Unsupported>().is_some());
+
+    Ok(())
+}
+
+// A batch cut short by a crash should not be applied at all
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key1".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 1)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"key3".to_vec())?, None);
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove(b"key1".to_vec()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(store.remove(b"key1".to_vec()).is_ok());
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into_bytes()));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Clones of the store should be usable from many threads at once
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(
+                            format!("key{}-{}", t, i).into_bytes(),
+                            format!("value{}", i).into_bytes(),
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i).into_bytes())?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i).into_bytes())?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Readers should always see a complete value while a writer overwrites and compacts
+#[test]
+fn concurrent_get_during_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id).into_bytes(), b"0".to_vec())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..50 {
+                for key_id in 0..10 {
+                    store
+                        .set(
+                            format!("key{}", key_id).into_bytes(),
+                            format!("{}", iter).into_bytes(),
+                        )
+                        .unwrap();
+                }
+            }
+        })
+    };
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    for key_id in 0..10 {
+                        let value = store.get(format!("key{}", key_id).into_bytes()).unwrap();
+                        let value: u32 = String::from_utf8(value.expect("key missing"))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        assert!(value < 50);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(b"49".to_vec())
+        );
+    }
+
+    Ok(())
+}
+
+// Scans read values without holding the index, so compaction should be
+// able to replace generations under them without losing any value
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .value_threshold(16)
+        .open(temp_dir.path())?;
+    let value = |iter: u32| format!("{:032}", iter).into_bytes();
+    for key_id in 0..100 {
+        store.set(format!("key{:02}", key_id).into_bytes(), value(0))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..20 {
+                for key_id in 0..100 {
+                    store
+                        .set(format!("key{:02}", key_id).into_bytes(), value(iter))
+                        .unwrap();
+                }
+                store.compaction().unwrap();
+            }
+        })
+    };
+
+    let scanners: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let pairs = store.scan(b"key".to_vec(), None, None).unwrap();
+                    assert_eq!(pairs.len(), 100);
+                    for (_, value) in pairs {
+                        assert_eq!(value.len(), 32);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for scanner in scanners {
+        scanner.join().unwrap();
+    }
+
+    assert_eq!(store.get(b"key42".to_vec())?, Some(value(19)));
+
+    Ok(())
+}
+
+fn log_files(temp_dir: &TempDir) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Compaction should replace the old generations with newer ones
+#[test]
+fn compaction_writes_new_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    assert_eq!(log_files(&temp_dir), vec!["1.log"]);
+
+    store.compaction()?;
+    assert_eq!(log_files(&temp_dir), vec!["2.log", "3.log"]);
+
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+fn hint_files(temp_dir: &TempDir) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".hint"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Compaction should write hints that opening the store loads instead of
+// replaying the generation, falling back to replaying it without them
+#[test]
+fn open_from_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().retained_versions(1);
+    let store = options.open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"old".to_vec())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.remove(b"key2".to_vec())?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    store.set_with_ttl(
+        b"session".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    store.compaction()?;
+    assert_eq!(hint_files(&temp_dir), vec!["2.hint"]);
+    // Written after the hints, so replayed from the log
+    store.set(b"key4".to_vec(), b"value4".to_vec())?;
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+        assert_eq!(
+            store.history(b"key1".to_vec(), None)?,
+            vec![version(2, Some(b"value1")), version(1, Some(b"old"))]
+        );
+        assert_eq!(store.get(b"key2".to_vec())?, None);
+        assert_eq!(
+            store.history(b"key2".to_vec(), None)?,
+            vec![version(4, None)]
+        );
+        assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+        assert_eq!(store.get(b"session".to_vec())?, None);
+        assert_eq!(store.get(b"key4".to_vec())?, Some(b"value4".to_vec()));
+        store.set(b"key5".to_vec(), b"value5".to_vec())?;
+        // Sequence numbers should carry on from the ones in the hints
+        assert!(store.history(b"key5".to_vec(), None)?[0].seq > 7);
+        store.remove(b"key5".to_vec())?;
+        Ok(())
+    };
+    check(&options.open(temp_dir.path())?)?;
+
+    // A damaged hint file should be ignored
+    let hint_path = temp_dir.path().join("2.hint");
+    let hints = fs::read(&hint_path)?;
+    fs::write(&hint_path, &hints[..hints.len() - 1])?;
+    check(&options.open(temp_dir.path())?)?;
+
+    // Compacting again should replace the hints along with the generations
+    fs::write(&hint_path, &hints)?;
+    let store = options.open(temp_dir.path())?;
+    store.remove(b"key4".to_vec())?;
+    store.compaction()?;
+    assert_eq!(hint_files(&temp_dir), vec!["4.hint"]);
+    drop(store);
+
+    // With hints, a damaged record is only noticed once it is read
+    let log_path = temp_dir.path().join("4.log");
+    let mut log = fs::read(&log_path)?;
+    let at = log.windows(6).position(|w| w == b"value3").unwrap();
+    log[at] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key4".to_vec())?, None);
+    let error = store.get(b"key3".to_vec()).unwrap_err();
+    assert!(error.downcast_ref::<CorruptRecord>().is_some());
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("4.hint"))?;
+    let error = options.open(temp_dir.path()).err().unwrap();
+    assert!(error.downcast_ref::<CorruptRecord>().is_some());
+
+    Ok(())
+}
+
+// Reads through memory maps should see the same values as reads through
+// the file, whether the record is in an old generation or the active one
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().mmap_reads(true).retained_versions(1);
+    let store = options.open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"old".to_vec())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.compaction()?;
+    // Written to the active generation, so read from the file
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+        assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+        assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+        assert_eq!(store.get_at(b"key1".to_vec(), 1)?, Some(b"old".to_vec()));
+        assert_eq!(
+            store.history(b"key1".to_vec(), None)?,
+            vec![version(2, Some(b"value1")), version(1, Some(b"old"))]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    check(&store.clone())?;
+
+    // The maps of generations compaction deleted should be let go of
+    store.set(b"key2".to_vec(), b"value2b".to_vec())?;
+    store.compaction()?;
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2b".to_vec()));
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    check(&store)?;
+    drop(store);
+    check(&options.open(temp_dir.path())?)?;
+
+    // A damaged record should be reported rather than returned
+    let log_path = temp_dir.path().join("4.log");
+    let mut log = fs::read(&log_path)?;
+    let at = log.windows(6).position(|w| w == b"value1").unwrap();
+    log[at] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+    let error = store.get(b"key1".to_vec()).unwrap_err();
+    assert!(error.downcast_ref::<CorruptRecord>().is_some());
+
+    Ok(())
+}
+
+// A compaction interrupted before its rename should leave the store intact
+#[test]
+fn ignore_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+
+    fs::write(temp_dir.path().join("2.log.compacting"), "{\"Set\":{\"ke")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert!(!temp_dir.path().join("2.log.compacting").exists());
+
+    Ok(())
+}
+
+// A log written in the old single-file layout should still open
+#[test]
+fn open_single_file_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("current_log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+         {\"Remove\":{\"key\":\"key2\"}}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(log_files(&temp_dir), vec!["1.log"]);
+
+    Ok(())
+}
+
+// A log of JSON records should be rewritten in the binary format on open
+#[test]
+fn migrate_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+         {\"Remove\":{\"key\":\"key2\"}}\n\
+         {\"Set\":{\"key\":\"ke",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(log_files(&temp_dir), vec!["1.log"]);
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVSLOG"));
+
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+fn wait_for_compaction(store: &KvStore) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.compaction_stats().runs == 0 {
+        assert!(Instant::now() < deadline, "no compaction finished");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Overwrites should not compact until enough stale bytes pile up
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    store.remove(b"key1".to_vec())?;
+    assert_eq!(log_files(&temp_dir), vec!["1.log"]);
+
+    for iter in 0..100 {
+        store.set(b"key1".to_vec(), format!("{}", iter).into_bytes())?;
+    }
+    wait_for_compaction(&store);
+    assert_ne!(log_files(&temp_dir), vec!["1.log"]);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"99".to_vec()));
+
+    Ok(())
+}
+
+// Stale bytes found while replaying the log should count towards the threshold
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .compaction_ratio(0.5);
+
+    let store = options.open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id).into_bytes(), b"value".to_vec())?;
+    }
+    for key_id in 0..9 {
+        store.set(format!("key{}", key_id).into_bytes(), b"value".to_vec())?;
+    }
+    assert_eq!(log_files(&temp_dir), vec!["1.log"]);
+
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    store.set(b"key9".to_vec(), b"value".to_vec())?;
+    wait_for_compaction(&store);
+    assert_ne!(log_files(&temp_dir), vec!["1.log"]);
+
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(b"value".to_vec())
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should run in the background while reads and writes continue
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .open(temp_dir.path())?;
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut iter = 0;
+    while store.compaction_stats().runs == 0 {
+        assert!(Instant::now() < deadline, "no compaction finished");
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes())?,
+                Some(format!("{}", iter).into_bytes())
+            );
+        }
+        iter += 1;
+    }
+
+    let stats = store.compaction_stats();
+    let last_run = stats.last_run.expect("missing last run");
+    assert!(last_run.bytes_reclaimed > 0);
+    assert!(last_run.bytes_written > 0);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{}", iter - 1).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+// Writes made while a compaction copies records should not be lost
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id).into_bytes(), b"old".to_vec())?;
+    }
+
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compaction())
+    };
+    for key_id in 0..1000u32 {
+        if key_id.is_multiple_of(2) {
+            store.set(format!("key{}", key_id).into_bytes(), b"new".to_vec())?;
+        } else if key_id.is_multiple_of(3) {
+            store.remove(format!("key{}", key_id).into_bytes())?;
+        }
+    }
+    compaction.join().unwrap()?;
+
+    let expected = |key_id: u32| {
+        if key_id.is_multiple_of(2) {
+            Some(b"new".to_vec())
+        } else if key_id.is_multiple_of(3) {
+            None
+        } else {
+            Some(b"old".to_vec())
+        }
+    };
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            expected(key_id)
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            expected(key_id)
+        );
+    }
+
+    Ok(())
+}
+
+// A half-written record at the end of the log should be dropped on open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    // Append the first part of a copy of the first record
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    let contents = fs::read(&log_path)?;
+    OpenOptions::new()
+        .append(true)
+        .open(&log_path)?
+        .write_all(&contents[8..24])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), len);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, None);
+
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// A damaged record followed by valid ones should be reported with its offset
+#[test]
+fn detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut contents = fs::read(&log_path)?;
+    // File header, then a 13-byte record header, the sequence number, the
+    // key and the value
+    let second = 8 + 13 + 8 + "key1".len() + "value1".len();
+    let value = second
+        + contents[second..]
+            .windows(6)
+            .position(|w| w == b"value2")
+            .unwrap();
+    contents[value] = b'V';
+    fs::write(&log_path, &contents)?;
+
+    let error = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    let corrupt = error
+        .downcast_ref::<CorruptRecord>()
+        .expect("unexpected error type");
+    assert_eq!(corrupt.gen, 1);
+    assert_eq!(corrupt.offset, second as u64);
+
+    Ok(())
+}
+
+// A record damaged in the middle of the log should be reported rather than
+// taken for a torn write, which would drop every record after it
+#[test]
+fn detect_damaged_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(
+            format!("key{}", key_id).into_bytes(),
+            format!("value{}", key_id).into_bytes(),
+        )?;
+    }
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut damaged = fs::read(&log_path)?;
+    // The last byte of the first record's value, after the file header, the
+    // record header, the sequence number and the key
+    damaged[8 + 13 + 8 + "key0".len() + "value0".len() - 1] ^= 0xff;
+    fs::write(&log_path, &damaged)?;
+
+    let error = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    let corrupt = error
+        .downcast_ref::<CorruptRecord>()
+        .expect("unexpected error type");
+    assert_eq!(corrupt.gen, 1);
+    assert_eq!(corrupt.offset, 8);
+    assert_eq!(fs::read(&log_path)?, damaged);
+
+    // The same goes for a batch
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key0".to_vec(), b"value0".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value1".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    store.write_batch(batch)?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut contents = fs::read(&log_path)?;
+    // File header, then a 13-byte record header, the sequence number, the
+    // key and the value, then the header of the batch
+    let batch = 8 + 13 + 8 + "key0".len() + "value0".len();
+    contents[batch + 13 + 20] ^= 0xff;
+    fs::write(&log_path, &contents)?;
+
+    let error = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    let corrupt = error
+        .downcast_ref::<CorruptRecord>()
+        .expect("unexpected error type");
+    assert_eq!(corrupt.offset, batch as u64);
+
+    Ok(())
+}
+
+// A record cut short should be truncated even if its value holds the bytes of
+// an intact record
+#[test]
+fn truncate_torn_record_holding_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"x".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let contents = fs::read(&log_path)?;
+    let mut value = contents[8..].to_vec();
+    value.extend_from_slice(&[b'y'; 60]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"b".to_vec(), value)?;
+    drop(store);
+
+    let torn_len = fs::metadata(&log_path)?.len() - 50;
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(torn_len)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"x".to_vec()));
+    assert_eq!(store.get(b"b".to_vec())?, None);
+    drop(store);
+    assert_eq!(fs::read(&log_path)?, contents);
+
+    Ok(())
+}
+
+fn value_files(temp_dir: &TempDir) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".vlog"))
+        .collect();
+    names.sort();
+    names
+}
+
+fn large_value(byte: u8) -> Vec<u8> {
+    vec![byte; 1000]
+}
+
+// Values over the threshold should be kept in the value log and read back
+// from it, through the index, history, snapshots and reopening
+#[test]
+fn value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .value_threshold(16)
+        .retained_versions(1);
+    let store = options.open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), large_value(1))?;
+    store.set(b"key1".to_vec(), large_value(2))?;
+    store.set(b"key2".to_vec(), b"small".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), large_value(3));
+    batch.set(b"key4".to_vec(), b"small".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(value_files(&temp_dir), vec!["1.vlog"]);
+    // Only where the large values are goes in the log
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() < 1000);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get(b"key1".to_vec())?, Some(large_value(2)));
+        assert_eq!(store.get_at(b"key1".to_vec(), 1)?, Some(large_value(1)));
+        assert_eq!(
+            store.history(b"key1".to_vec(), None)?,
+            vec![
+                version(2, Some(&large_value(2))),
+                version(1, Some(&large_value(1)))
+            ]
+        );
+        assert_eq!(store.get(b"key2".to_vec())?, Some(b"small".to_vec()));
+        assert_eq!(store.get(b"key3".to_vec())?, Some(large_value(3)));
+        assert_eq!(store.get(b"key4".to_vec())?, Some(b"small".to_vec()));
+        assert_eq!(
+            store.snapshot()?.get(b"key3".to_vec())?,
+            Some(large_value(3))
+        );
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&options.open(temp_dir.path())?)?;
+
+    // A third of the value log is stale, too little to be worth copying, so
+    // compaction should leave it where it is
+    let store = options.open(temp_dir.path())?;
+    store.compaction()?;
+    assert_eq!(value_files(&temp_dir), vec!["1.vlog"]);
+    let run = store.compaction_stats().last_run.unwrap();
+    assert_eq!(run.value_bytes_copied, 0);
+    assert_eq!(run.value_bytes_reclaimed, 0);
+    check(&store)?;
+    drop(store);
+    check(&options.open(temp_dir.path())?)?;
+    fs::remove_file(temp_dir.path().join("2.hint"))?;
+    check(&options.open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Compaction should copy the live values out of a mostly stale value log
+// file and delete it once no snapshot can read from it
+#[test]
+fn value_log_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .value_threshold(16)
+        .retained_versions(1);
+    let store = options.open(temp_dir.path())?;
+
+    store.set(b"key1".to_vec(), large_value(1))?;
+    store.set(b"key1".to_vec(), large_value(2))?;
+    store.set(b"key2".to_vec(), large_value(3))?;
+    store.set(b"key3".to_vec(), large_value(4))?;
+    store.compaction()?;
+    let snapshot = store.snapshot()?;
+
+    // Only key2 still needs 1.vlog, once the history of the other keys has
+    // moved on
+    store.set(b"key1".to_vec(), large_value(5))?;
+    store.set(b"key1".to_vec(), large_value(6))?;
+    store.set(b"key3".to_vec(), large_value(7))?;
+    store.set(b"key3".to_vec(), large_value(8))?;
+    store.compaction()?;
+    let run = store.compaction_stats().last_run.unwrap();
+    // Every entry holds a 12-byte header, the key and the value
+    let entry_len = 12 + 4 + 1000;
+    assert_eq!(run.value_bytes_copied, entry_len);
+    assert_eq!(run.value_bytes_reclaimed, 3 * entry_len);
+    assert_eq!(value_files(&temp_dir), vec!["1.vlog", "3.vlog", "4.vlog"]);
+    assert_eq!(snapshot.get(b"key1".to_vec())?, Some(large_value(2)));
+    assert_eq!(snapshot.get(b"key3".to_vec())?, Some(large_value(4)));
+    drop(snapshot);
+    assert_eq!(value_files(&temp_dir), vec!["3.vlog", "4.vlog"]);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get(b"key1".to_vec())?, Some(large_value(6)));
+        assert_eq!(store.get_at(b"key1".to_vec(), 5)?, Some(large_value(5)));
+        assert_eq!(store.get(b"key2".to_vec())?, Some(large_value(3)));
+        assert_eq!(store.get(b"key3".to_vec())?, Some(large_value(8)));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&options.open(temp_dir.path())?)?;
+
+    // A damaged value should be reported rather than returned
+    let value_path = temp_dir.path().join("3.vlog");
+    let mut values = fs::read(&value_path)?;
+    let at = values.windows(4).rposition(|w| w == b"key3").unwrap();
+    values[at + 100] ^= 0xff;
+    fs::write(&value_path, &values)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(large_value(6)));
+    let error = store.get(b"key3".to_vec()).unwrap_err();
+    let corrupt = error
+        .downcast_ref::<CorruptValue>()
+        .expect("unexpected error type");
+    assert_eq!(corrupt.gen, 3);
+
+    Ok(())
+}
+
+// Overwriting large values should queue a compaction once enough of the value
+// log is stale, even while the log itself stays small
+#[test]
+fn value_log_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .value_threshold(16)
+        .compaction_threshold(64 * 1024)
+        .open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set(b"key1".to_vec(), large_value(iter))?;
+    }
+    wait_for_compaction(&store);
+    assert!(!value_files(&temp_dir).contains(&"1.vlog".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(large_value(99)));
+
+    Ok(())
+}
+
+fn cached_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let store = CachedEngine::new(engine, 100);
+    let stats = |hits, misses| {
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses), (hits, misses));
+    };
+
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"value".to_vec()));
+    stats(1, 1);
+    assert_eq!(store.stats().entries, 1);
+    assert_eq!(store.stats().bytes, 8);
+
+    // Every kind of write should drop the key, through any clone
+    let clone = store.clone();
+    clone.set(b"key".to_vec(), b"changed".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"changed".to_vec()));
+    clone.compare_and_swap(
+        b"key".to_vec(),
+        Some(b"changed".to_vec()),
+        Some(b"swapped".to_vec()),
+    )?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"swapped".to_vec()));
+    let mut batch = WriteBatch::new();
+    batch.set(b"key".to_vec(), b"batched".to_vec());
+    clone.write_batch(batch)?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"batched".to_vec()));
+    clone.transaction(|tx| {
+        tx.get(b"key".to_vec())?;
+        tx.set(b"key".to_vec(), b"7".to_vec());
+        Ok(())
+    })?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"7".to_vec()));
+    clone.incr(b"key".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"8".to_vec()));
+    clone.remove(b"key".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, None);
+    assert_eq!(store.stats().entries, 0);
+    // The transaction read through the cache, a hit
+    stats(2, 7);
+
+    // Expiring keys should be cached only until they expire
+    store.set_with_ttl(
+        b"session".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(store.get(b"session".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"session".to_vec())?, Some(b"value".to_vec()));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get(b"session".to_vec())?, None);
+    stats(3, 9);
+
+    // The same key in another namespace should be cached separately
+    let users = store.namespace("users")?;
+    store.set(b"key".to_vec(), b"default".to_vec())?;
+    users.set(b"key".to_vec(), b"user".to_vec())?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"default".to_vec()));
+    assert_eq!(users.get(b"key".to_vec())?, Some(b"user".to_vec()));
+    assert_eq!(users.get(b"key".to_vec())?, Some(b"user".to_vec()));
+    drop(users);
+    assert!(store.drop_namespace("users")?);
+    assert_eq!(store.namespace("users")?.get(b"key".to_vec())?, None);
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"default".to_vec()));
+    store.remove(b"key".to_vec())?;
+    stats(5, 12);
+
+    // Least recently used pairs should make room for new ones: each of these
+    // takes 22 of the 100 bytes
+    for i in 0..5 {
+        store.set(format!("k{}", i).into_bytes(), vec![b'v'; 20])?;
+        store.get(format!("k{}", i).into_bytes())?;
+    }
+    assert_eq!(store.stats().evictions, 1);
+    assert_eq!(store.stats().entries, 4);
+    assert_eq!(store.stats().bytes, 88);
+    store.get(b"k1".to_vec())?;
+    store.get(b"k0".to_vec())?;
+    store.get(b"k1".to_vec())?;
+    store.get(b"k2".to_vec())?;
+    assert_eq!(store.stats().evictions, 3);
+    stats(7, 19);
+
+    // A pair bigger than the whole cache should not be cached
+    store.set(b"big".to_vec(), vec![b'v'; 100])?;
+    store.get(b"big".to_vec())?;
+    store.get(b"big".to_vec())?;
+    stats(7, 21);
+    assert_eq!(store.stats().evictions, 3);
+
+    Ok(())
+}
+
+// A cache in front of an engine should count hits and misses, and never
+// return a value that was written over
+#[test]
+fn cached_engine_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    cached_engine(KvStore::open(temp_dir.path())?)?;
+
+    // Cached values should survive compaction, which does not change them
+    let store = CachedEngine::new(KvStore::open(temp_dir.path())?, 1 << 20);
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    store.get(b"key".to_vec())?;
+    store.engine().compaction()?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.stats().hits, 1);
+    store.set(b"key".to_vec(), b"changed".to_vec())?;
+    store.engine().compaction()?;
+    assert_eq!(store.get(b"key".to_vec())?, Some(b"changed".to_vec()));
+
+    // The engine should behave the same with the cache in front
+    let cached = |temp_dir: &TempDir| -> Result<CachedEngine<KvStore>> {
+        Ok(CachedEngine::new(KvStore::open(temp_dir.path())?, 1 << 20))
+    };
+    expiring_keys(&cached(&TempDir::new()?)?)?;
+    write_batches(&cached(&TempDir::new()?)?)?;
+    compare_and_swaps(&cached(&TempDir::new()?)?)?;
+    counters(&cached(&TempDir::new()?)?)?;
+    transactions(&cached(&TempDir::new()?)?)?;
+    namespaces(&cached(&TempDir::new()?)?)?;
+
+    Ok(())
+}
+
+#[test]
+fn cached_engine_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    cached_engine(SledKvStore::open(temp_dir.path())?)
 }
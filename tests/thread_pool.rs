@@ -0,0 +1,81 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{NoThreads, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+const JOBS: usize = 100;
+
+fn run_jobs<P: ThreadPool>(pool: &P) {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..JOBS {
+        let counter = Arc::clone(&counter);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..JOBS {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("job did not finish");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+}
+
+#[test]
+fn naive_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&NaiveThreadPool::new(4)?);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&SharedQueueThreadPool::new(4)?);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&RayonThreadPool::new(4)?);
+    Ok(())
+}
+
+// Panicking jobs should not use up the pool's workers
+#[test]
+fn shared_queue_thread_pool_survives_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..10 {
+        pool.spawn(|| panic!("job panicked"));
+    }
+    run_jobs(&pool);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_survives_panics() -> Result<()> {
+    let pool = RayonThreadPool::new(2)?;
+    for _ in 0..10 {
+        pool.spawn(|| panic!("job panicked"));
+    }
+    run_jobs(&pool);
+    Ok(())
+}
+
+#[test]
+fn thread_pools_refuse_zero_threads() {
+    let errors = [
+        NaiveThreadPool::new(0).err(),
+        SharedQueueThreadPool::new(0).err(),
+        RayonThreadPool::new(0).err(),
+    ];
+
+    for error in errors {
+        let error = error.expect("pool created with no threads");
+        assert!(error.downcast_ref::<NoThreads>().is_some());
+    }
+}